@@ -46,6 +46,10 @@ opcodes = {
     "shr": "1000",
     "sshr": "1000",
     "shl": "1000",
+    "mul": "1000",
+    "mulh": "1000",
+    "div": "1000",
+    "mod": "1000",
 
     "iand": "1001",
     "ior": "1001",
@@ -57,6 +61,10 @@ opcodes = {
     "ishr": "1001",
     "isshr": "1001",
     "ishl": "1001",
+    "imul": "1001",
+    "imulh": "1001",
+    "idiv": "1001",
+    "imod": "1001",
 }
 
 one_4bit_opcodes = ["not", "tst"]
@@ -66,7 +74,9 @@ two_4bit_opcodes = [
     "xor", "add", "sub", "mov", "cmp",
     "shr", "sshr", "shl", "iand",
     "ior", "ixor", "iadd", "isub", "icmp",
-    "ishr", "isshr", "ishl"
+    "ishr", "isshr", "ishl",
+    "mul", "mulh", "div", "mod",
+    "imul", "imulh", "idiv", "imod"
 ]
 
 load_str_opcodes = [
@@ -127,6 +137,10 @@ opcodes_suffix = {
     "shr": "1000",
     "sshr": "1001",
     "shl": "1010",
+    "mul": "1011",
+    "mulh": "1100",
+    "div": "1101",
+    "mod": "1110",
 
     "iand": "0001",
     "ior": "0010",
@@ -138,6 +152,10 @@ opcodes_suffix = {
     "ishr": "1000",
     "isshr": "1001",
     "ishl": "1010",
+    "imul": "1011",
+    "imulh": "1100",
+    "idiv": "1101",
+    "imod": "1110",
 }
 
 named_registers = {
@@ -480,7 +498,8 @@ def optimize(instructions: List[Instruction]) -> List[Instruction]:
 
 class Program:
     def __init__(self):
-        pass
+        # (address, kind, name, size) of every label, filled in by parse
+        self.symbols: List[Tuple[int, str, str, Optional[int]]] = []
 
     def parse_ignore(self, token) -> Optional[Ignore]:
         if token == "fn": return Ignore()
@@ -571,6 +590,8 @@ class Program:
 
     def parse(self, lines: List[str]) -> list[tuple[str, str]]:
         raw_format = []
+        # labels declared with `fn`, they start functions
+        functions = set()
         for line in lines:
             stripped_line = line.strip()
             line_to_parse = re.split("#|@|//", stripped_line)[0].strip()
@@ -585,6 +606,8 @@ class Program:
             raw = [self.parse_token(tok.strip()) for tok in line_to_parse.split()]
             if len(raw) > 0 and isinstance(raw[0], Ignore):
                 raw.pop(0)
+                if len(raw) > 0 and isinstance(raw[0], Label):
+                    functions.add(raw[0].name)
 
             if len(raw) < 1 or raw[0] is None:
                 continue
@@ -605,8 +628,10 @@ class Program:
 
             if len(line) >= 2 and isinstance(line[0], Label) and isinstance(line[1], Number):
                 label_locations[line[0]] = line[1]
+                self.symbols.append((line[1].number, "const", line[0].name, None))
             elif len(line) >= 2 and isinstance(line[0], Label) and isinstance(line[1], Allocation):
                 label_locations[line[0]] = Number(heap)
+                self.symbols.append((heap, "data", line[0].name, line[1].size))
                 heap += line[1].size
             elif isinstance(line[0], Label):
                 current_labels_for_line.append(line[0])
@@ -628,6 +653,7 @@ class Program:
                 if label in label_locations:
                     raise Exception(f"Label {label} is a duplicate")
                 label_locations[label] = Number(i)
+                self.symbols.append((i, "fn" if label.name in functions else "code", label.name, None))
 
         # for label, loc in label_locations.items():
         #     print (f"{label} at {loc}")
@@ -672,11 +698,26 @@ def preprocess(root_file: str, included: Set[str]) -> List[str]:
     return final_lines
 
 
+def write_symbols(file_name: str, symbols: List[Tuple[int, str, str, Optional[int]]]):
+    """
+    One label per line as address, kind (fn, code, data or const), name and, for allocations, the size
+    in words. Addresses and sizes are hex.
+    """
+    with open(file_name, "w", encoding="utf-8") as f:
+        f.write("# toast symbols\n")
+        for addr, kind, name, size in sorted(symbols, key=lambda s: (s[0], s[2])):
+            line = f"{addr & 0xFFFF:04X} {kind} {name}"
+            if size is not None:
+                line += f" {size:X}"
+            f.write(line + "\n")
+
+
 def main():
     import argparse
     parser = argparse.ArgumentParser(description="Assembles ToastCPU Architecture")
     parser.add_argument("-i", "--input_file", type=str, required=True, help="Input .tasm file")
     parser.add_argument("-o", "--output_file", type=str, default="a.out", help="Output file location")
+    parser.add_argument("-s", "--symbol_file", type=str, help="Also write the label addresses to this file, for the emulator")
     args = parser.parse_args()
 
     i, o = args.input_file, args.output_file
@@ -707,6 +748,9 @@ BEGIN
 
         f.write("END;")
 
+    if args.symbol_file is not None:
+        write_symbols(args.symbol_file, program.symbols)
+
 
 if __name__ == "__main__":
     main()
@@ -4,6 +4,7 @@ use crate::emit::types::*;
 use crate::parser_util::types::*;
 use crate::util::gss::Stack;
 use crate::util::labels::{function_label, generate_label_with_context};
+use crate::{target, Target};
 use lrpar::Span;
 
 use super::types::FunctionType;
@@ -210,6 +211,34 @@ pub fn emit_operator(
                 &[u16!()],
             )?;
         }
+        Operator::Mul(span) | Operator::Div(span) | Operator::Rem(span) => {
+            let (name, op) = match r {
+                Operator::Mul(_) => ("*", "mul"),
+                Operator::Div(_) => ("/", "div"),
+                _ => ("%", "mod"),
+            };
+            if target() != Target::MulDiv {
+                return Err((
+                    *span,
+                    format!("`{name}` needs the hardware {op} instruction, compile with --target muldiv"),
+                ));
+            }
+            tasm!(
+                operation;;
+                r"
+    pop!  t0 t1
+    {op}  t1 t0
+    push! t1
+                            "
+            );
+            check_and_apply_stack_transition(
+                name,
+                span,
+                stack_view,
+                &vec![u16!(), u16!()],
+                &[u16!()],
+            )?;
+        }
 
         Operator::LOr(span) => {
             let true_label = format!("{block_id}_ortrue");
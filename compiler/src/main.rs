@@ -5,6 +5,7 @@ use std::collections::HashSet;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::fs;
+use std::str::FromStr;
 extern crate argparse;
 extern crate core;
 
@@ -24,11 +25,37 @@ mod preprocess;
 mod util;
 
 static VERBOSE: OnceCell<bool> = OnceCell::new();
+static TARGET: OnceCell<Target> = OnceCell::new();
 
 pub fn is_verbose() -> bool {
     *VERBOSE.get().unwrap_or(&false)
 }
 
+/// The ToastCPU variant to emit code for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// The base instruction set.
+    Base,
+    /// With the `mul`, `div` and `mod` alu operations, needed for `*`, `/` and `%`.
+    MulDiv,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base" => Ok(Target::Base),
+            "muldiv" => Ok(Target::MulDiv),
+            _ => Err(format!("Unknown target {s}, expected base or muldiv")),
+        }
+    }
+}
+
+pub fn target() -> Target {
+    *TARGET.get().unwrap_or(&Target::Base)
+}
+
 #[derive(Debug)]
 struct ArgParseError(i32);
 
@@ -46,6 +73,7 @@ struct Options {
     include_paths: Vec<String>,
     verbose: bool,
     only_preprocess: bool,
+    target: Target,
 }
 
 fn get_args() -> Result<Options, ArgParseError> {
@@ -54,6 +82,7 @@ fn get_args() -> Result<Options, ArgParseError> {
     let mut include_paths: Vec<String> = vec![];
     let mut verbose: bool = false;
     let mut only_preprocess: bool = false;
+    let mut target: Target = Target::Base;
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("Compile ToastLang to ToastASM");
@@ -70,6 +99,8 @@ fn get_args() -> Result<Options, ArgParseError> {
             .add_option(&["-v", "--verbose"], StoreTrue, "Verbose mode");
         ap.refer(&mut only_preprocess)
             .add_option(&["-p", "--only-preprocess"], StoreTrue, "Emit the preprocessed code, and do not compile to tasm");
+        ap.refer(&mut target)
+            .add_option(&["-t", "--target"], Store, "Instruction set to target, base or muldiv");
         ap.parse_args()
     }
     .map(|_| Options {
@@ -78,6 +109,7 @@ fn get_args() -> Result<Options, ArgParseError> {
         include_paths,
         verbose,
         only_preprocess,
+        target,
     })
     .map_err(ArgParseError)
 }
@@ -85,6 +117,7 @@ fn get_args() -> Result<Options, ArgParseError> {
 fn run() -> Result<(), String> {
     let options = get_args().map_err(|e| e.to_string())?;
     VERBOSE.set(options.verbose).expect("Cannot set VERBOSE flag twice. This is a bug inside the program, please contact the owner to resolve it.");
+    TARGET.set(options.target).expect("Cannot set TARGET twice. This is a bug inside the program, please contact the owner to resolve it.");
 
     if is_verbose() {
         println!("{options:?}");
@@ -13,6 +13,9 @@ pub enum Operator {
     Shr(Span),
     Shl(Span),
     Xor(Span),
+    Mul(Span),
+    Div(Span),
+    Rem(Span),
 
     LOr(Span),
     LAnd(Span),
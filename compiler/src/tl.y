@@ -108,6 +108,9 @@ Operator -> Result<Operator, ()>:
     | 'SHR'     { Ok(Operator::Shr($span))      }
     | 'SHL'     { Ok(Operator::Shl($span))      }
     | 'XOR'     { Ok(Operator::Xor($span))      }
+    | 'PTR'     { Ok(Operator::Mul($span))      }
+    | 'DIV'     { Ok(Operator::Div($span))      }
+    | 'REM'     { Ok(Operator::Rem($span))      }
     | 'LOR'     { Ok(Operator::LOr($span))      }
     | 'LAND'    { Ok(Operator::LAnd($span))     }
     | 'LNOT'    { Ok(Operator::LNot($span))     }
@@ -1,10 +1,15 @@
 use std::{error::Error, fmt::{Display, Formatter}};
 
-use argparse::{ArgumentParser, StoreTrue, Store};
+use argparse::{ArgumentParser, Collect, StoreTrue, Store};
+
+use emu::devices::keyboard::DEFAULT_DEPTH;
+use emu::screen::dump::DumpFormat;
+
+use crate::frames::DEFAULT_FRAME_INTERVAL;
 
 
 #[derive(Debug)]
-pub struct ArgParseError(i32);
+pub struct ArgParseError(pub i32);
 
 impl Error for ArgParseError {}
 impl Display for ArgParseError {
@@ -15,24 +20,169 @@ impl Display for ArgParseError {
 #[derive(Debug)]
 pub struct Options {
     pub mif_file: String,
+    pub format: String,
+    pub load: Vec<String>,
+    pub symbols: String,
     pub jit_mode: bool,
+    pub disasm: bool,
+    pub headless: bool,
+    pub debug: bool,
+    pub trap_debug: bool,
+    pub gdb: String,
+    pub dump_file: String,
+    pub dump_format: DumpFormat,
+    pub trace_file: String,
+    pub trace_range: String,
+    pub trace_ring: usize,
+    pub load_state: String,
+    pub save_state: String,
+    pub stop_after: u64,
+    pub record: String,
+    pub replay: String,
+    pub uart: String,
+    pub key_fifo: usize,
+    pub screenshot: String,
+    pub frames: String,
+    pub frame_interval: u64,
+    pub cast: String,
+    pub profile: String,
+    pub profile_folded: String,
+    pub check: bool,
+    pub stack_limit: String,
+    pub expect: String,
 }
 
 pub fn get_args() -> Result<Options, ArgParseError> {
     let mut mif_file: String = "".to_string();
+    let mut format: String = "".to_string();
+    let mut load: Vec<String> = Vec::new();
+    let mut symbols: String = "".to_string();
     let mut jit_mode: bool = false;
+    let mut disasm: bool = false;
+    let mut headless: bool = false;
+    let mut debug: bool = false;
+    let mut trap_debug: bool = false;
+    let mut gdb: String = "".to_string();
+    let mut dump_file: String = "-".to_string();
+    let mut dump_format: DumpFormat = DumpFormat::Text;
+    let mut trace_file: String = "".to_string();
+    let mut trace_range: String = "".to_string();
+    let mut trace_ring: usize = 0;
+    let mut load_state: String = "".to_string();
+    let mut save_state: String = "".to_string();
+    let mut stop_after: u64 = 0;
+    let mut record: String = "".to_string();
+    let mut replay: String = "".to_string();
+    let mut uart: String = "".to_string();
+    let mut key_fifo: usize = DEFAULT_DEPTH;
+    let mut screenshot: String = "".to_string();
+    let mut frames: String = "".to_string();
+    let mut frame_interval: u64 = DEFAULT_FRAME_INTERVAL;
+    let mut cast: String = "".to_string();
+    let mut profile: String = "".to_string();
+    let mut profile_folded: String = "".to_string();
+    let mut check: bool = false;
+    let mut stack_limit: String = "".to_string();
+    let mut expect: String = "".to_string();
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("Emulate toastcpu");
         ap.refer(&mut jit_mode)
             .add_option(&["-j", "--jit"], StoreTrue, "Just in time compiled mode");
+        ap.refer(&mut disasm)
+            .add_option(&["--disasm"], StoreTrue, "Print the disassembled rom and exit");
+        ap.refer(&mut headless)
+            .add_option(&["--headless"], StoreTrue, "Run without a terminal, dumping the screen on halt");
+        ap.refer(&mut debug)
+            .add_option(&["-d", "--debug"], StoreTrue, "Start in the interactive debugger");
+        ap.refer(&mut trap_debug)
+            .add_option(&["--trap-debug"], StoreTrue, "Start in the debugger and stop there on faults, instead of entering the program's trap handler");
+        ap.refer(&mut gdb)
+            .add_option(&["--gdb"], Store, "Serve gdb on a localhost tcp port, or a unix socket path");
+        ap.refer(&mut dump_file)
+            .add_option(&["--dump"], Store, "Where to write the screen dump in headless mode, - for stdout");
+        ap.refer(&mut dump_format)
+            .add_option(&["--dump-format"], Store, "Format of the screen dump, text or json");
+        ap.refer(&mut trace_file)
+            .add_option(&["--trace"], Store, "Log every executed instruction to this file");
+        ap.refer(&mut trace_range)
+            .add_option(&["--trace-range"], Store, "Only trace pcs in these START:END ranges, comma separated");
+        ap.refer(&mut trace_ring)
+            .add_option(&["--trace-ring"], Store, "Only keep the last N traced instructions, written out on error");
+        ap.refer(&mut load_state)
+            .add_option(&["--load-state"], Store, "Start from a machine state snapshot");
+        ap.refer(&mut save_state)
+            .add_option(&["--save-state"], Store, "Save a machine state snapshot when the program stops");
+        ap.refer(&mut stop_after)
+            .add_option(&["--stop-after"], Store, "Stop after N instructions, 0 to run until halt");
+        ap.refer(&mut record)
+            .add_option(&["--record"], Store, "Log every key interrupt and when it was taken to this file");
+        ap.refer(&mut replay)
+            .add_option(&["--replay"], Store, "Replay key interrupts from a recording, without a terminal");
+        ap.refer(&mut uart)
+            .add_option(&["--uart"], Store, "Attach a uart bridged to stdio (headless only), file:PATH (output only) or unix:PATH");
+        ap.refer(&mut key_fifo)
+            .add_option(&["--key-fifo"], Store, "How many keys the keyboard fifo holds before dropping them");
+        ap.refer(&mut screenshot)
+            .add_option(&["--screenshot"], Store, "Render the screen to this png file when the program stops");
+        ap.refer(&mut frames)
+            .add_option(&["--frames"], Store, "Render numbered png frames to PREFIX00000.png, PREFIX00001.png, ...");
+        ap.refer(&mut frame_interval)
+            .add_option(&["--frame-interval"], Store, "Instructions between two --frames frames");
+        ap.refer(&mut cast)
+            .add_option(&["--cast"], Store, "Record the terminal display to an asciinema v2 cast file");
+        ap.refer(&mut profile)
+            .add_option(&["--profile"], Store, "Count executed instructions and write a hot spot report per function and pc to this file");
+        ap.refer(&mut profile_folded)
+            .add_option(&["--profile-folded"], Store, "Write the profiled call stacks in folded format, for flamegraph tools");
+        ap.refer(&mut check)
+            .add_option(&["--check"], StoreTrue, "Stop on reads of unwritten ram or of vram through rom, stack overflows and push/pop leaving ram");
+        ap.refer(&mut stack_limit)
+            .add_option(&["--stack-limit"], Store, "Lowest address sp may reach with --check (default the end of the globals, or 0x8000)");
+        ap.refer(&mut expect)
+            .add_option(&["--expect"], Store, "Run without a display until halt or the instruction limit, then check the assertions in this expectations file");
+        ap.refer(&mut format)
+            .add_option(&["--format"], Store, "Format of the rom file, mif, hex, bin-le or bin-be (default from its extension)");
+        ap.refer(&mut load)
+            .add_option(&["--load"], Collect, "Also load an image at an address, as FILE@ADDR or FILE@ADDR:FORMAT, repeatable");
+        ap.refer(&mut symbols)
+            .add_option(&["--symbols"], Store, "Symbol file for naming addresses (default the rom file with a .sym extension, if it exists)");
         ap.refer(&mut mif_file)
-            .add_argument("MIF_FILE", Store, "The rom file");
+            .add_argument("MIF_FILE", Store, "The rom file, a mif, intel hex or raw binary image");
         ap.parse_args()
     }
     .map(|_| Options {
         mif_file,
+        format,
+        load,
+        symbols,
         jit_mode,
+        disasm,
+        headless,
+        debug: debug || trap_debug,
+        trap_debug,
+        gdb,
+        dump_file,
+        dump_format,
+        trace_file,
+        trace_range,
+        trace_ring,
+        load_state,
+        save_state,
+        stop_after,
+        record,
+        replay,
+        uart,
+        key_fifo,
+        screenshot,
+        frames,
+        frame_interval,
+        cast,
+        profile,
+        profile_folded,
+        check,
+        stack_limit,
+        expect,
     })
     .map_err(ArgParseError)
 }
@@ -0,0 +1,506 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::devices::{exceptions, interrupts, Devices};
+use crate::hardware::def::*;
+use crate::hardware::register::{Registers, StatusRegister, StatusRegisterFlag};
+use crate::error::{Error, Fault};
+
+const SP_INDEX: u16 = 13;
+
+fn bit(n: i32, bit: u8) -> bool {
+    (n >> bit) & 1 != 0
+}
+
+/// Runs alu operation `op` on the zero extended words `a` and `b`, setting the flags in `sr`.
+/// Returns none for the one op code without an operation, leaving `sr` alone.
+pub fn alu(op: u16, a: i32, b: i32, sr: &mut StatusRegister) -> Option<u16> {
+    let agg: i32 = match op {
+        0x0 => !a,
+        0x1 => a & b,
+        0x2 => a | b,
+        0x3 => a ^ b,
+        0x4 => a + b,
+        0x5 => a - b,
+        0x6 => b,
+        0x7 => a - b,
+        0x8 => ((a as u16) >> b) as i32,
+        0x9 => ((a as i16) >> b) as i32,
+        0xA => a << b,
+        0xB => ((a as u32 * b as u32) & 0xFFFF) as i32,
+        0xC => ((a as u32 * b as u32) >> 16) as i32,
+        0xD => if b == 0 { 0xFFFF } else { a / b },
+        0xE => if b == 0 { a } else { a % b },
+        _ => return None,
+    };
+
+    sr.set(StatusRegisterFlag::N, agg & 0x8000 != 0);
+    sr.set(StatusRegisterFlag::Z, agg == 0);
+    sr.set(StatusRegisterFlag::X, agg == 0xFFFF);
+
+    match op {
+        0x4 => {
+            sr.set(
+                StatusRegisterFlag::V,
+                (bit(a, 15) == bit(b, 15)) && (bit(a, 15) ^ bit(agg, 15)),
+            );
+            sr.set(StatusRegisterFlag::C, bit(agg, 16));
+        }
+        0x5 | 0x7 => {
+            sr.set(
+                StatusRegisterFlag::V,
+                (bit(a, 15) ^ bit(b, 15)) && (bit(a, 15) != bit(agg, 15)),
+            );
+            sr.set(StatusRegisterFlag::C, bit(agg, 16));
+        }
+        0xB => {
+            let signed = (a as i16 as i32) * (b as i16 as i32);
+            sr.set(StatusRegisterFlag::V, signed != signed as i16 as i32);
+            sr.set(StatusRegisterFlag::C, a as u32 * b as u32 > 0xFFFF);
+        }
+        0xC => {
+            // the high word is unsigned, a signed overflow of the low word says nothing about it
+            sr.set(StatusRegisterFlag::V, false);
+            sr.set(StatusRegisterFlag::C, agg != 0);
+        }
+        0xD | 0xE => {
+            // dividing by zero gives all ones for div and leaves a for mod, flagged by V
+            sr.set(StatusRegisterFlag::V, b == 0);
+            sr.set(StatusRegisterFlag::C, false);
+        }
+        _ => (),
+    }
+
+    Some((agg & 0xFFFF) as u16)
+}
+
+/// What a single call to `step` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// A pending interrupt was taken, no instruction was executed.
+    Interrupt,
+    /// The instruction faulted and the trap handler was entered instead, see `exceptions`.
+    Trap,
+    Instruction,
+    Halt,
+}
+
+/// Passes the irq line and device requests to the interrupt controller, then enters the isr if
+/// an unmasked interrupt is pending and the cpu is not already servicing one. Pushes pc and sr,
+/// sets the I flag so the isr is not interrupted, and jumps to isr. RTI restores sr, which clears
+/// I again. Returns whether an interrupt was taken.
+pub fn interrupt(
+    registers: &mut Registers,
+    mem: &mut Devices,
+    irq: &Mutex<bool>,
+) -> Result<bool, Error> {
+    if std::mem::take(&mut *irq.lock().unwrap()) {
+        mem.request_irq(interrupts::KEYBOARD);
+    }
+    mem.poll_irqs();
+    if registers.sr.get(StatusRegisterFlag::I) || mem.take_irq().is_none() {
+        return Ok(false);
+    }
+
+    let pc = registers.pc;
+    let result = (|| {
+        if let Some(checks) = mem.checks() {
+            checks
+                .stack(registers.sp.wrapping_sub(2), mem.symbols())
+                .map_err(Error::memory)?;
+        }
+        registers.sp -= 1;
+        mem.write(registers.sp, registers.pc)?;
+        registers.sp -= 1;
+        mem.write(registers.sp, registers.sr.sr)
+    })();
+    result.map_err(|e| {
+        e.context("Entering the isr").at(|| Fault {
+            pc,
+            inst: None,
+            registers: registers.values(),
+        })
+    })?;
+    registers.sr.set(StatusRegisterFlag::I, true);
+    registers.pc = registers.isr;
+    Ok(true)
+}
+
+/// Enters the trap handler for `e`, if the program can catch it and has a handler ready. The
+/// registers have to be as they were before the faulting instruction. Saves pc and sr in the
+/// exception unit, sets the I flag and jumps to the vector. Returns whether it did.
+pub fn trap(registers: &mut Registers, mem: &mut Devices, e: &Error) -> bool {
+    let (Some((cause, addr)), Some(fault)) = (exceptions::cause(e), e.fault()) else {
+        return false;
+    };
+    let Some(vector) = mem.exceptions().vector() else {
+        return false;
+    };
+    mem.exceptions_mut()
+        .enter(cause, fault.pc, registers.sr.sr, fault.inst.unwrap_or(0), addr);
+    registers.sr.set(StatusRegisterFlag::I, true);
+    registers.pc = vector;
+    true
+}
+
+/// A toast cpu and the memory bus it is attached to. Nothing here knows about terminals or
+/// threads, front ends drive it with `step` and share the irq line and instruction counter
+/// through the handles.
+pub struct Cpu {
+    pub registers: Registers,
+    pub mem: Devices,
+    irq: Arc<Mutex<bool>>,
+    count: Arc<AtomicU64>,
+    traps: bool,
+}
+
+impl Cpu {
+    pub fn new(rom: Vec<u16>) -> Cpu {
+        Cpu {
+            registers: Registers::new(),
+            mem: Devices::new(rom),
+            irq: Arc::new(Mutex::new(false)),
+            count: Arc::new(AtomicU64::new(0)),
+            traps: true,
+        }
+    }
+
+    /// The irq line, for devices that raise interrupts from other threads.
+    pub fn irq_handle(&self) -> Arc<Mutex<bool>> {
+        Arc::clone(&self.irq)
+    }
+
+    /// The instruction counter, for displays running on other threads.
+    pub fn count_handle(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.count)
+    }
+
+    /// Instructions executed so far, interrupts taken are not counted.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn set_count(&mut self, count: u64) {
+        self.count.store(count, Ordering::Relaxed);
+    }
+
+    /// Requests an interrupt, taken before the next instruction.
+    pub fn raise_irq(&self) {
+        *self.irq.lock().unwrap() = true;
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        *self.irq.lock().unwrap()
+    }
+
+    pub fn set_irq_pending(&self, pending: bool) {
+        *self.irq.lock().unwrap() = pending;
+    }
+
+    /// Whether faults enter the program's trap handler. With traps off `step` returns them as
+    /// errors even if there is a handler, for debuggers to stop on.
+    pub fn set_traps(&mut self, traps: bool) {
+        self.traps = traps;
+    }
+
+    pub fn read(&mut self, addr: u16) -> Result<u16, Error> {
+        self.mem.read(addr)
+    }
+
+    /// Reads memory without triggering device side effects.
+    pub fn peek(&self, addr: u16) -> Result<u16, Error> {
+        self.mem.peek(addr)
+    }
+
+    pub fn write(&mut self, addr: u16, val: u16) -> Result<(), Error> {
+        self.mem.write(addr, val)
+    }
+
+    /// The state of the cpu executing `inst` at `pc`, for errors.
+    fn fault(&self, pc: u16, inst: Option<u16>) -> Fault {
+        Fault {
+            pc,
+            inst,
+            registers: self.registers.values(),
+        }
+    }
+
+    /// Enters the trap handler for `e`, see `trap`. Returns whether it did.
+    pub fn trap(&mut self, e: &Error) -> bool {
+        trap(&mut self.registers, &mut self.mem, e)
+    }
+
+    /// Takes a pending interrupt, or executes the instruction at pc. Faults enter the trap
+    /// handler if there is one, see `set_traps`.
+    pub fn step(&mut self) -> Result<Step, Error> {
+        let pc = self.registers.pc;
+        let step = match self.execute() {
+            Err(e) if self.traps && self.trap(&e) => Step::Trap,
+            result => result?,
+        };
+        if step == Step::Instruction || step == Step::Halt {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.mem.tick(1);
+        }
+        if let Some(checks) = self.mem.checks() {
+            checks
+                .stack(self.registers.sp, self.mem.symbols())
+                .map_err(|e| Error::memory(e).at(|| self.fault(pc, self.peek(pc).ok())))?;
+        }
+        Ok(step)
+    }
+
+    /// In checked mode, makes sure sp stays above the stack limit and a push or pop through any
+    /// other register stays in ram.
+    fn check_stack_access(&self, reg: u16) -> Result<(), Error> {
+        let Some(checks) = self.mem.checks() else {
+            return Ok(());
+        };
+        let result = if reg == SP_INDEX {
+            checks.stack(self.registers.sp, self.mem.symbols())
+        } else {
+            checks.stack_access(reg, self.registers[reg], self.mem.symbols())
+        };
+        result.map_err(Error::memory)
+    }
+
+    /// Steps until `cond` holds before an instruction, or the program halts. Returns the last
+    /// step taken, `Step::Halt` if the program halted.
+    pub fn run_until<F>(&mut self, mut cond: F) -> Result<Step, Error>
+    where
+        F: FnMut(&Cpu) -> bool,
+    {
+        let mut last = Step::Instruction;
+        while !cond(self) {
+            last = self.step()?;
+            if last == Step::Halt {
+                break;
+            }
+        }
+        Ok(last)
+    }
+
+    fn execute(&mut self) -> Result<Step, Error> {
+        if interrupt(&mut self.registers, &mut self.mem, &self.irq)? {
+            return Ok(Step::Interrupt);
+        }
+
+        let pc = self.registers.pc;
+        let inst: u16 = self
+            .mem
+            .fetch(pc)
+            .map_err(|e| e.at(|| self.fault(pc, None)))?;
+        let before = self.registers.values();
+        self.execute_inst(inst).map_err(|e| {
+            // faults the program can catch leave the registers as they were, so the handler
+            // can retry or skip the instruction
+            if exceptions::cause(&e).is_some() {
+                self.registers.set_values(&before);
+            }
+            e.at(|| self.fault(pc, Some(inst)))
+        })
+    }
+
+    fn execute_inst(&mut self, inst: u16) -> Result<Step, Error> {
+        let mut halt: bool = false;
+
+        let opcode: u16 = (inst & 0xF000) >> 12;
+
+        let r1: u16 = (inst & 0x0F00) >> 8;
+        let r2: u16 = (inst & 0x00F0) >> 4;
+        let imoh_imm8: u16 = (inst & 0x00FF) << 8;
+        let imov_imm8: u16 = (inst & 0x00FF) | (if (inst & 0x0080) == 0 { 0x0000 } else { 0xFF00 });
+
+        let alu_imm4: u16 = r2;
+        let alu_op: u16 = inst & 0x000F;
+        let load_offset: u16 = alu_op;
+        let jmp_op: u16 = alu_op;
+
+        match opcode {
+            LOAD => {
+                self.registers[r1] = self.mem.read(self.registers[r2] + load_offset)?;
+            }
+            STR => {
+                self.mem
+                    .write(self.registers[r1] + load_offset, self.registers[r2])?;
+            }
+            IMOV => {
+                self.registers[r1] = imov_imm8;
+            }
+            IMOH => {
+                self.registers[r1] = imoh_imm8 | (self.registers[r1] & 0x00FF);
+            }
+            PUSH => {
+                self.registers[r1] -= 1;
+                self.check_stack_access(r1)?;
+                self.mem.write(self.registers[r1], self.registers[r2])?;
+            }
+            POP => {
+                if r2 != SP_INDEX {
+                    self.check_stack_access(r2)?;
+                }
+                self.registers[r1] = self.mem.read(self.registers[r2])?;
+                self.registers[r2] += 1;
+            }
+            HALT => {
+                halt = true;
+            }
+            ALU | IALU => {
+                let b = if opcode == ALU { self.registers[r2] } else { alu_imm4 };
+                let agg = alu(
+                    alu_op,
+                    self.registers[r1] as i32,
+                    b as i32,
+                    &mut self.registers.sr,
+                )
+                .ok_or_else(|| Error::BadAluOp {
+                    op: alu_op,
+                    fault: self.fault(self.registers.pc, Some(inst)),
+                })?;
+
+                if alu_op != 0x7 {
+                    self.registers[r1] = agg;
+                }
+            }
+            JMP => {
+                let l: bool = r2 & 1 != 0;
+                let r: bool = r2 & 2 != 0;
+
+                let do_jump: bool = match jmp_op {
+                    0 => true,
+                    1 => self.registers.sr.get(StatusRegisterFlag::Z),
+                    2 => !self.registers.sr.get(StatusRegisterFlag::Z),
+                    3 => self.registers.sr.get(StatusRegisterFlag::N),
+                    4 => {
+                        !self.registers.sr.get(StatusRegisterFlag::Z)
+                            && !self.registers.sr.get(StatusRegisterFlag::N)
+                    }
+                    _ => false,
+                };
+
+                if do_jump {
+                    if r {
+                        self.registers.pc = self.mem.read(self.registers.sp)?;
+                        self.registers.sp += 1;
+                    } else if l {
+                        self.registers.sp -= 1;
+                        self.check_stack_access(SP_INDEX)?;
+                        self.mem.write(self.registers.sp, self.registers.pc + 1)?;
+                        self.registers.pc = self.registers[r1];
+                    } else {
+                        self.registers.pc = self.registers[r1];
+                    }
+                    self.registers.pc -= 1;
+                }
+            }
+            RTI => {
+                self.registers.sr.sr = self.mem.read(self.registers.sp)?;
+                self.registers.sp += 1;
+                self.registers.pc = self.mem.read(self.registers.sp)?;
+                self.registers.sp += 1;
+                self.registers.pc -= 1;
+            }
+            _ => {
+                return Err(Error::IllegalInstruction {
+                    fault: self.fault(self.registers.pc, Some(inst)),
+                })
+            }
+        }
+        self.registers.pc += 1;
+
+        Ok(if halt { Step::Halt } else { Step::Instruction })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use StatusRegisterFlag::*;
+
+    fn run(op: u16, a: u16, b: u16) -> (Option<u16>, StatusRegister) {
+        let mut sr = StatusRegister { sr: 0 };
+        let result = alu(op, a as i32, b as i32, &mut sr);
+        (result, sr)
+    }
+
+    #[test]
+    fn add_sets_carry_and_overflow() {
+        let (result, sr) = run(0x4, 0xFFFF, 0x0002);
+        assert_eq!(result, Some(0x0001));
+        assert!(sr.get(C) && !sr.get(V) && !sr.get(N));
+
+        let (result, sr) = run(0x4, 0x7FFF, 0x0001);
+        assert_eq!(result, Some(0x8000));
+        assert!(sr.get(N) && sr.get(V) && !sr.get(C));
+    }
+
+    #[test]
+    fn sub_borrows() {
+        let (result, sr) = run(0x5, 0x0001, 0x0003);
+        assert_eq!(result, Some(0xFFFE));
+        assert!(sr.get(N) && sr.get(C) && !sr.get(V));
+
+        let (result, sr) = run(0x7, 0x1234, 0x1234);
+        assert_eq!(result, Some(0));
+        assert!(sr.get(Z) && !sr.get(N) && !sr.get(C));
+
+        let (result, sr) = run(0x7, 0x8000, 0x0001);
+        assert_eq!(result, Some(0x7FFF));
+        assert!(sr.get(V) && !sr.get(N));
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(run(0x8, 0x8000, 15).0, Some(0x0001));
+        assert_eq!(run(0x9, 0x8000, 15).0, Some(0xFFFF));
+        assert_eq!(run(0xA, 0x0001, 15).0, Some(0x8000));
+    }
+
+    #[test]
+    fn mul_and_mulh() {
+        // 0xFFFF is 65535 unsigned and -1 signed
+        let (result, sr) = run(0xB, 0xFFFF, 0xFFFF);
+        assert_eq!(result, Some(0x0001));
+        assert!(sr.get(C) && !sr.get(V));
+
+        let (result, sr) = run(0xC, 0xFFFF, 0xFFFF);
+        assert_eq!(result, Some(0xFFFE));
+        assert!(sr.get(C) && !sr.get(V));
+
+        let (result, sr) = run(0xC, 0x4000, 0x0004);
+        assert_eq!(result, Some(0x0001));
+        assert!(sr.get(C) && !sr.get(V));
+
+        // the low word overflows signed, but the high word is 0
+        let (result, sr) = run(0xC, 0x00FF, 0x00FF);
+        assert_eq!(result, Some(0));
+        assert!(!sr.get(C) && !sr.get(V));
+
+        let (result, sr) = run(0xB, 0x0100, 0x0010);
+        assert_eq!(result, Some(0x1000));
+        assert!(!sr.get(C) && !sr.get(V));
+    }
+
+    #[test]
+    fn div_and_mod_by_zero() {
+        let (result, sr) = run(0xD, 0x1234, 0);
+        assert_eq!(result, Some(0xFFFF));
+        assert!(sr.get(V) && sr.get(X) && !sr.get(C));
+
+        let (result, sr) = run(0xE, 0x1234, 0);
+        assert_eq!(result, Some(0x1234));
+        assert!(sr.get(V));
+
+        let (result, sr) = run(0xD, 100, 7);
+        assert_eq!(result, Some(14));
+        assert!(!sr.get(V));
+        assert_eq!(run(0xE, 100, 7).0, Some(2));
+    }
+
+    #[test]
+    fn op_f_is_illegal() {
+        let mut sr = StatusRegister { sr: 0x003F };
+        assert_eq!(alu(0xF, 1, 2, &mut sr), None);
+        assert_eq!(sr.sr, 0x003F);
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeSet;
+use std::io::{stdin, stdout, BufRead, Write};
+use std::sync::Arc;
+
+use emu::disasm::disassemble;
+use emu::devices::exceptions;
+use emu::hardware::def::JMP;
+use emu::hardware::register::{StatusRegisterFlag, REGISTER_NAMES};
+use emu::loader::Program;
+use emu::screen::raster::save_png;
+use emu::screen::rows;
+use emu::snapshot::Snapshot;
+use emu::symbols::{Addr, Symbols};
+use emu::{Cpu, Error, Step};
+
+use crate::args::Options;
+use crate::emulator;
+use crate::frames::Frames;
+use crate::input::Input;
+
+const HELP: &str = "\
+commands:
+  b, break ADDR        set a breakpoint on pc
+  d, delete ADDR       remove a breakpoint
+  bl, breakpoints      list breakpoints
+  s, step [N]          execute N instructions (default 1)
+  n, next              step, running over jumps with the link bit
+  c, continue          run until a breakpoint, halt or error
+  trap                 enter the program's trap handler for the fault that stopped it
+  r, regs              print registers
+  f, flags             print the decoded status register
+  x, mem ADDR [LEN]    print LEN words of memory starting at ADDR (default 8)
+  l, list [ADDR] [LEN] disassemble LEN instructions from ADDR (default pc, 8)
+  screen               print the text on the vga screen
+  screenshot FILE      render the vga screen to a png
+  devices              list the devices in the io window
+  save FILE            save a machine state snapshot
+  load FILE            restore a machine state snapshot
+  h, help              print this message
+  q, quit              exit the debugger
+numbers are decimal or 0x prefixed hex, register names evaluate to their value and symbols
+to their address.
+an empty line repeats the last command.";
+
+enum Stop {
+    Steps,
+    Breakpoint,
+    Halt,
+    Error(Error),
+}
+
+struct Debugger<'a> {
+    cpu: &'a mut Cpu,
+    breakpoints: BTreeSet<u16>,
+    input: Input,
+    frames: Option<Frames>,
+    halted: bool,
+    /// The fault the cpu stopped on, if the program could catch it.
+    fault: Option<Error>,
+}
+
+impl<'a> Debugger<'a> {
+    fn addr(&self, addr: u16) -> Addr<'_> {
+        Addr(addr, self.cpu.mem.symbols())
+    }
+
+    fn value(&self, s: &str) -> Result<u16, String> {
+        if let Some(r) = REGISTER_NAMES.iter().position(|n| *n == s) {
+            return Ok(self.cpu.registers[r as u16]);
+        }
+        if let Some(r) = s.strip_prefix('r').and_then(|r| r.parse::<u16>().ok()) {
+            if r < 16 {
+                return Ok(self.cpu.registers[r]);
+            }
+        }
+
+        if let Some(addr) = self.cpu.mem.symbols().find(s) {
+            return Ok(addr);
+        }
+
+        let parsed = match s.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => s.parse::<u16>(),
+        };
+        parsed.map_err(|_| format!("{s} is not a number, register or symbol"))
+    }
+
+    fn print_location(&self) {
+        let pc = self.cpu.registers.pc;
+        match self.cpu.peek(pc) {
+            Ok(inst) => println!("{}: {inst:04x}  {}", self.addr(pc), disassemble(inst)),
+            Err(e) => println!("{}: {}", self.addr(pc), e.display(self.cpu.mem.symbols())),
+        }
+    }
+
+    fn print_registers(&self) {
+        for row in 0..4u16 {
+            let line: Vec<String> = (0..4u16)
+                .map(|col| {
+                    let r = row * 4 + col;
+                    let name = format!("r{r} ({})", REGISTER_NAMES[r as usize]);
+                    format!("{name:<9} = {:#06x}", self.cpu.registers[r])
+                })
+                .collect();
+            println!("{}", line.join("   "));
+        }
+    }
+
+    fn print_flags(&self) {
+        let sr = &self.cpu.registers.sr;
+        let flag = |f: StatusRegisterFlag| u8::from(sr.get(f));
+        println!(
+            "sr = {:#06x}  I={} V={} C={} N={} Z={} X={}",
+            sr.sr,
+            flag(StatusRegisterFlag::I),
+            flag(StatusRegisterFlag::V),
+            flag(StatusRegisterFlag::C),
+            flag(StatusRegisterFlag::N),
+            flag(StatusRegisterFlag::Z),
+            flag(StatusRegisterFlag::X),
+        );
+    }
+
+    fn print_memory(&self, addr: u16, len: u16) {
+        for line in (0..len).step_by(8) {
+            let start = addr.wrapping_add(line);
+            let words: Vec<String> = (line..len.min(line + 8))
+                .map(|i| match self.cpu.peek(addr.wrapping_add(i)) {
+                    Ok(val) => format!("{val:04x}"),
+                    Err(_) => "????".to_string(),
+                })
+                .collect();
+            println!("{}: {}", self.addr(start), words.join(" "));
+        }
+    }
+
+    fn print_listing(&self, addr: u16, len: u16) {
+        for i in 0..len {
+            let at = addr.wrapping_add(i);
+            for label in self.cpu.mem.symbols().labels_at(at) {
+                println!("{label}:");
+            }
+            let marker = if at == self.cpu.registers.pc { "=>" } else { "  " };
+            match self.cpu.peek(at) {
+                Ok(inst) => println!("{marker} {at:#06x}: {inst:04x}  {}", disassemble(inst)),
+                Err(e) => println!("{marker} {at:#06x}: {}", e.display(self.cpu.mem.symbols())),
+            }
+        }
+    }
+
+    fn print_screen(&self) {
+        for row in rows(self.cpu.mem.vram()) {
+            let line: String = row.iter().map(|c| c.printable()).collect();
+            println!("|{line}|");
+        }
+    }
+
+    /// Executes up to `limit` steps, stopping early on breakpoints. `until` is a temporary stop
+    /// at a pc, only taken once sp is back at or above the given value.
+    fn resume(&mut self, limit: Option<u64>, until: Option<(u16, u16)>) -> Stop {
+        if self.halted {
+            return Stop::Halt;
+        }
+        self.fault = None;
+
+        let mut steps = 0;
+        loop {
+            self.input.before_step(self.cpu);
+            let result = self.cpu.step();
+            if result.is_ok() {
+                if let Err(e) = self.input.after_step(self.cpu) {
+                    return Stop::Error(e);
+                }
+                if let Some(Err(e)) = self.frames.as_mut().map(|f| f.after_step(self.cpu)) {
+                    return Stop::Error(e);
+                }
+            }
+            match result {
+                Ok(Step::Halt) => {
+                    self.halted = true;
+                    return Stop::Halt;
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    if exceptions::cause(&e).is_some() {
+                        self.fault = Some(e.clone());
+                    }
+                    return Stop::Error(e);
+                }
+            }
+
+            steps += 1;
+            let pc = self.cpu.registers.pc;
+            if until.is_some_and(|(addr, sp)| pc == addr && self.cpu.registers.sp >= sp) {
+                return Stop::Steps;
+            }
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint;
+            }
+            if limit.is_some_and(|limit| steps >= limit) {
+                return Stop::Steps;
+            }
+        }
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Steps => (),
+            Stop::Breakpoint => println!("breakpoint at {}", self.addr(self.cpu.registers.pc)),
+            Stop::Halt => {
+                println!(
+                    "halted at {} after {} instructions",
+                    self.addr(self.cpu.registers.pc.wrapping_sub(1)),
+                    self.cpu.count()
+                );
+                return;
+            }
+            Stop::Error(e) => {
+                println!("error: {}", e.display(self.cpu.mem.symbols()));
+                if self.fault.is_some() && self.cpu.mem.exceptions().vector().is_some() {
+                    println!("the program has a trap handler, trap enters it");
+                }
+            }
+        }
+        self.print_location();
+    }
+
+    fn next(&mut self) -> Stop {
+        let pc = self.cpu.registers.pc;
+        let inst = match self.cpu.peek(pc) {
+            Ok(inst) => inst,
+            Err(_) => return self.resume(Some(1), None),
+        };
+
+        let r2 = (inst & 0x00F0) >> 4;
+        let is_call = (inst & 0xF000) >> 12 == JMP && r2 & 1 != 0 && r2 & 2 == 0;
+        if is_call {
+            self.resume(None, Some((pc.wrapping_add(1), self.cpu.registers.sp)))
+        } else {
+            self.resume(Some(1), None)
+        }
+    }
+
+    /// Runs a single command, returning false when the debugger should exit.
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = |i: usize| -> Result<u16, String> {
+            words
+                .get(i)
+                .ok_or_else(|| format!("{} expects an argument", words[0]))
+                .and_then(|w| self.value(w))
+        };
+
+        match words[0] {
+            "b" | "break" => {
+                let addr = arg(1)?;
+                self.breakpoints.insert(addr);
+                println!("breakpoint set at {}", self.addr(addr));
+            }
+            "d" | "delete" => {
+                let addr = arg(1)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {}", self.addr(addr)));
+                }
+            }
+            "bl" | "breakpoints" => {
+                for addr in &self.breakpoints {
+                    println!("{}", self.addr(*addr));
+                }
+            }
+            "s" | "step" => {
+                let n = if words.len() > 1 { arg(1)? } else { 1 };
+                let stop = self.resume(Some(n as u64), None);
+                self.report(stop);
+            }
+            "n" | "next" => {
+                let stop = self.next();
+                self.report(stop);
+            }
+            "c" | "continue" => {
+                let stop = self.resume(None, None);
+                self.report(stop);
+            }
+            "trap" => {
+                let fault = self.fault.take().ok_or("the cpu did not stop on a fault")?;
+                if !self.cpu.trap(&fault) {
+                    self.fault = Some(fault);
+                    return Err("the program has no trap handler ready".to_string());
+                }
+                self.print_location();
+            }
+            "r" | "regs" => self.print_registers(),
+            "f" | "flags" => self.print_flags(),
+            "x" | "mem" => {
+                let addr = arg(1)?;
+                let len = if words.len() > 2 { arg(2)? } else { 8 };
+                self.print_memory(addr, len);
+            }
+            "l" | "list" => {
+                let addr = if words.len() > 1 { arg(1)? } else { self.cpu.registers.pc };
+                let len = if words.len() > 2 { arg(2)? } else { 8 };
+                self.print_listing(addr, len);
+            }
+            "screen" => self.print_screen(),
+            "screenshot" => {
+                let path = words.get(1).ok_or("screenshot expects a file")?;
+                save_png(self.cpu.mem.vram(), path)?;
+                println!("saved screenshot to {path}");
+            }
+            "devices" => {
+                for (name, start, end) in self.cpu.mem.attached() {
+                    println!("{start:#06x}..={end:#06x}  {name}");
+                }
+            }
+            "save" => {
+                let path = words.get(1).ok_or("save expects a file")?;
+                Snapshot::capture(self.cpu)
+                    .save(path)
+                    .map_err(|e| e.to_string())?;
+                println!("saved state to {path}");
+            }
+            "load" => {
+                let path = words.get(1).ok_or("load expects a file")?;
+                Snapshot::load(path)
+                    .and_then(|snapshot| snapshot.restore(self.cpu))
+                    .map_err(|e| e.to_string())?;
+                self.halted = false;
+                self.fault = None;
+                self.print_location();
+            }
+            "h" | "help" => println!("{HELP}"),
+            "q" | "quit" => return Ok(false),
+            cmd => return Err(format!("unknown command {cmd}, try help")),
+        }
+        Ok(true)
+    }
+
+    fn repl(&mut self) -> Result<(), String> {
+        println!("toast debugger, type help for a list of commands");
+        self.print_location();
+
+        let mut last = String::new();
+        let mut lines = stdin().lock().lines();
+        loop {
+            print!("(tdb) ");
+            stdout().flush().map_err(|e| format!("{e}"))?;
+
+            let line = match lines.next() {
+                Some(line) => line.map_err(|e| format!("Could not read command: {e}"))?,
+                None => return Ok(()),
+            };
+
+            let line = if line.trim().is_empty() { last.clone() } else { line };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match self.command(&line) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(e) => println!("{e}"),
+            }
+            last = line;
+        }
+    }
+}
+
+/// Runs `rom` under the interactive debugger.
+pub fn debug(program: Program, opts: &Options, symbols: &Arc<Symbols>) -> Result<(), Error> {
+    let input = Input::from_options(opts)?;
+    let frames = Frames::from_options(opts)?;
+    emulator::run(program, opts, symbols, |cpu| {
+        cpu.set_traps(!opts.trap_debug);
+        Debugger {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            input,
+            frames,
+            halted: false,
+            fault: None,
+        }
+        .repl()
+        .map_err(Error::Io)
+    })
+}
@@ -0,0 +1,178 @@
+use crate::hardware::def::{RAM_SIZE, ROM_SIZE, VGA_HEIGHT, VGA_WIDTH};
+use crate::hardware::register::REGISTER_NAMES;
+use crate::symbols::{Addr, Symbols};
+
+/*
+ *  The checked mode turns mistakes the hardware lets through silently into errors:
+ *
+ *  - reading a ram word nothing has written since reset
+ *  - reading back an address below 0x8000 the program wrote to, the write went to vram but
+ *    reads there return rom. Only addresses outside the loaded image and its symbols count,
+ *    reading rom strings and constants after drawing over the same cells is how programs work
+ *  - sp dropping below the stack limit, into the globals or out of ram
+ *  - push and pop through any other register, like the tl return stack in t5, leaving ram
+ *
+ *  Instruction fetches are not checked.
+ */
+
+/// State of the checked mode, see `Devices::enable_checks`.
+pub struct Checks {
+    stack_limit: u16,
+    /// Ram words written since reset, or set by the loaded image.
+    initialized: Vec<bool>,
+    /// Vram cells the program has written.
+    vram_written: Vec<bool>,
+    /// Rom words set by the loaded image, reading those is intended.
+    image: Vec<bool>,
+}
+
+fn in_ram(addr: u16) -> bool {
+    (0x8000..=0xBFFF).contains(&addr)
+}
+
+impl Checks {
+    /// `initialized` marks the ram words that already hold data, one per word of ram, and
+    /// `image` the rom words the loaded image set, one per word of rom.
+    pub fn new(stack_limit: u16, initialized: Vec<bool>, image: Vec<bool>) -> Checks {
+        assert_eq!(initialized.len(), RAM_SIZE);
+        assert_eq!(image.len(), ROM_SIZE);
+        Checks {
+            stack_limit,
+            initialized,
+            vram_written: vec![false; VGA_WIDTH * VGA_HEIGHT],
+            image,
+        }
+    }
+
+    pub fn stack_limit(&self) -> u16 {
+        self.stack_limit
+    }
+
+    /// Treats all of ram as written, after it was replaced from a snapshot.
+    pub fn initialize_all(&mut self) {
+        self.initialized.fill(true);
+    }
+
+    pub(crate) fn read(&self, addr: u16, symbols: &Symbols) -> Result<(), String> {
+        if in_ram(addr) && !self.initialized[(addr - 0x8000) as usize] {
+            return Err(format!(
+                "Memory location {} is read before anything was written to it",
+                Addr(addr, symbols)
+            ));
+        }
+        if self.vram_written.get(addr as usize) == Some(&true)
+            && !self.image[addr as usize]
+            && !symbols.covers(addr)
+        {
+            return Err(format!(
+                "Memory location {} was written as vram, but reading it returns rom",
+                Addr(addr, symbols)
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write(&mut self, addr: u16) {
+        if in_ram(addr) {
+            self.initialized[(addr - 0x8000) as usize] = true;
+        } else if let Some(written) = self.vram_written.get_mut(addr as usize) {
+            *written = true;
+        }
+    }
+
+    /// Checks sp against the stack limit.
+    pub fn stack(&self, sp: u16, symbols: &Symbols) -> Result<(), String> {
+        if sp < self.stack_limit {
+            return Err(format!(
+                "Stack overflow, sp={} is below the stack limit {}",
+                Addr(sp, symbols),
+                Addr(self.stack_limit, symbols)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks a push or pop through register `reg` at `addr` stays in ram.
+    pub fn stack_access(&self, reg: u16, addr: u16, symbols: &Symbols) -> Result<(), String> {
+        if !in_ram(addr) {
+            return Err(format!(
+                "Stack in {} left ram, it points at {}",
+                REGISTER_NAMES[reg as usize],
+                Addr(addr, symbols)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::loader::Program;
+    use crate::{Cpu, Error, Step};
+
+    /// Clears two screen cells, then copies the rom string at 0x40 onto them.
+    const PRINT: [u16; 9] = [
+        0x2140, // imov r1 0x40
+        0x2220, // imov r2 ' '
+        0x1120, // str r1 r2 0
+        0x1121, // str r1 r2 1
+        0x0310, // load r3 r1 0
+        0x1130, // str r1 r3 0
+        0x0311, // load r3 r1 1
+        0x1131, // str r1 r3 1
+        0x7000, // halt
+    ];
+
+    fn run(program: Program, symbols: Symbols) -> Result<Step, Error> {
+        let mut cpu = Cpu::new(program.rom);
+        cpu.mem.set_symbols(Arc::new(symbols));
+        cpu.mem
+            .enable_checks(Checks::new(0x8000, program.loaded, program.rom_loaded));
+        let result = cpu.run_until(|_| false);
+        if result.is_ok() {
+            let vram = cpu.mem.vram();
+            assert_eq!(vram[0x40].load(Ordering::Relaxed), 0x48);
+            assert_eq!(vram[0x41].load(Ordering::Relaxed), 0x69);
+        }
+        result
+    }
+
+    fn program(string: bool) -> Program {
+        let mut program = Program::default();
+        let words: Vec<(u32, u16)> = PRINT
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (i as u32, *w))
+            .collect();
+        program.place(0, &words).unwrap();
+        if string {
+            program.place(0x40, &[(0, 0x48), (1, 0x69)]).unwrap();
+        } else {
+            program.rom[0x40] = 0x48;
+            program.rom[0x41] = 0x69;
+        }
+        program
+    }
+
+    #[test]
+    fn rom_strings_after_clearing_the_screen() {
+        assert_eq!(run(program(true), Symbols::EMPTY).unwrap(), Step::Halt);
+    }
+
+    #[test]
+    fn symbols_cover_rom_outside_the_image() {
+        let symbols = Symbols::parse("0040 data message 2\n").unwrap();
+        assert_eq!(run(program(false), symbols).unwrap(), Step::Halt);
+    }
+
+    #[test]
+    fn vram_read_back() {
+        let e = run(program(false), Symbols::EMPTY).unwrap_err();
+        assert!(matches!(e, Error::Memory { .. }));
+        assert!(e.to_string().contains("0x0040 was written as vram"), "{e}");
+    }
+}
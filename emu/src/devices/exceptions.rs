@@ -0,0 +1,126 @@
+use crate::error::Error;
+
+/*
+ *  Register map, as offsets from EXC_BASE:
+ *
+ *  0 VECTOR    address of the trap handler, 0 at reset. While it is 0 faults stop the emulator
+ *  1 CAUSE     why the last trap was taken, see the causes below. Writing any value clears it
+ *  2 EPC       pc of the faulting instruction, read only
+ *  3 ESR       sr at the fault, read only
+ *  4 INST      the faulting instruction word, 0 if it could not be fetched, read only
+ *  5 ADDR      the address a memory fault tried to access, read only
+ *
+ *  A fault rolls the registers back to before the faulting instruction, saves pc and sr in EPC
+ *  and ESR, sets the I flag and jumps to VECTOR. Nothing is pushed, the stack may be what
+ *  faulted. A handler returns by pushing EPC, or EPC + 1 to skip the instruction, and ESR, then
+ *  clearing CAUSE and executing rti.
+ *
+ *  A fault while CAUSE is still set, a double fault, stops the emulator. So do faults while
+ *  entering an interrupt and failing --check checks.
+ */
+
+pub const VECTOR: u16 = 0;
+pub const CAUSE: u16 = 1;
+pub const EPC: u16 = 2;
+pub const ESR: u16 = 3;
+pub const INST: u16 = 4;
+pub const ADDR: u16 = 5;
+
+/// Number of registers, the unit takes up `EXC_BASE..EXC_BASE + SIZE`.
+pub const SIZE: u16 = 6;
+
+// Causes
+pub const ILLEGAL_INSTRUCTION: u16 = 1;
+pub const ILLEGAL_ALU_OP: u16 = 2;
+pub const UNMAPPED: u16 = 3;
+
+/// The cause and ADDR value of an error the program can catch, none for the others.
+pub fn cause(e: &Error) -> Option<(u16, u16)> {
+    match e {
+        Error::IllegalInstruction { fault } => Some((ILLEGAL_INSTRUCTION, fault.pc)),
+        Error::BadAluOp { fault, .. } => Some((ILLEGAL_ALU_OP, fault.pc)),
+        Error::Unmapped { addr, .. } => Some((UNMAPPED, *addr)),
+        _ => None,
+    }
+}
+
+pub fn cause_name(cause: u16) -> &'static str {
+    match cause {
+        ILLEGAL_INSTRUCTION => "illegal instruction",
+        ILLEGAL_ALU_OP => "illegal alu operation",
+        UNMAPPED => "unmapped memory",
+        _ => "none",
+    }
+}
+
+/// Holds the trap vector and what the cpu saved on the last trap.
+#[derive(Default)]
+pub struct ExceptionUnit {
+    vector: u16,
+    cause: u16,
+    epc: u16,
+    esr: u16,
+    inst: u16,
+    addr: u16,
+}
+
+impl ExceptionUnit {
+    pub fn new() -> ExceptionUnit {
+        ExceptionUnit::default()
+    }
+
+    /// The handler to enter on a fault, none if there is no handler or it has not finished with
+    /// the last trap.
+    pub fn vector(&self) -> Option<u16> {
+        (self.vector != 0 && self.cause == 0).then_some(self.vector)
+    }
+
+    /// Records a trap.
+    pub fn enter(&mut self, cause: u16, pc: u16, sr: u16, inst: u16, addr: u16) {
+        self.cause = cause;
+        self.epc = pc;
+        self.esr = sr;
+        self.inst = inst;
+        self.addr = addr;
+    }
+
+    /// All registers in register map order, for snapshots.
+    pub fn state(&self) -> [u16; SIZE as usize] {
+        [self.vector, self.cause, self.epc, self.esr, self.inst, self.addr]
+    }
+
+    pub fn restore(&mut self, [vector, cause, epc, esr, inst, addr]: [u16; SIZE as usize]) {
+        *self = ExceptionUnit {
+            vector,
+            cause,
+            epc,
+            esr,
+            inst,
+            addr,
+        };
+    }
+
+    pub fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            VECTOR => self.vector,
+            CAUSE => self.cause,
+            EPC => self.epc,
+            ESR => self.esr,
+            INST => self.inst,
+            _ => self.addr,
+        }
+    }
+
+    pub fn write(&mut self, offset: u16, val: u16) -> Result<(), Error> {
+        match offset {
+            VECTOR => self.vector = val,
+            CAUSE => self.cause = 0,
+            _ => {
+                return Err(Error::memory(
+                    "only VECTOR and CAUSE of the exception unit are writable",
+                ))
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use crate::error::Error;
+
+/*
+ *  Register map, as offsets from INTC_BASE:
+ *
+ *  0 PENDING   bit n set while line n has an untaken request or is held asserted, writing 1s
+ *              clears requests, lines stay pending for as long as they are asserted
+ *  1 MASK      bit n set lets line n interrupt, all lines are unmasked at reset
+ *  2 SOURCE    line of the interrupt taken last, read only
+ *
+ *  Lower lines win when several are pending. Requests on masked lines stay pending.
+ */
+
+pub const PENDING: u16 = 0;
+pub const MASK: u16 = 1;
+pub const SOURCE: u16 = 2;
+
+/// Number of registers, the controller takes up `INTC_BASE..INTC_BASE + SIZE`.
+pub const SIZE: u16 = 3;
+
+// Interrupt lines, in priority order
+pub const TIMER: u16 = 0;
+pub const KEYBOARD: u16 = 1;
+pub const UART: u16 = 2;
+
+/// Collects interrupt requests from the devices and hands the cpu the highest priority one.
+pub struct InterruptController {
+    pending: u16,
+    asserted: u16,
+    mask: u16,
+    source: u16,
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        InterruptController::new()
+    }
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController {
+            pending: 0,
+            asserted: 0,
+            mask: 0xFFFF,
+            source: 0,
+        }
+    }
+
+    /// Latches a request on `line`, pending until it is taken or cleared.
+    pub fn request(&mut self, line: u16) {
+        self.pending |= 1 << line;
+    }
+
+    /// Holds `line` pending for as long as `asserted` is true, for level triggered devices.
+    pub fn assert(&mut self, line: u16, asserted: bool) {
+        if asserted {
+            self.asserted |= 1 << line;
+        } else {
+            self.asserted &= !(1 << line);
+        }
+    }
+
+    /// Takes the highest priority unmasked request, latching its line into SOURCE.
+    pub fn take(&mut self) -> Option<u16> {
+        let ready = (self.pending | self.asserted) & self.mask;
+        if ready == 0 {
+            return None;
+        }
+        let line = ready.trailing_zeros() as u16;
+        self.pending &= !(1 << line);
+        self.source = line;
+        Some(line)
+    }
+
+    /// Pending requests, mask and source, for snapshots. Asserted lines are left out, the
+    /// devices assert them again on the next poll.
+    pub fn state(&self) -> [u16; 3] {
+        [self.pending, self.mask, self.source]
+    }
+
+    pub fn restore(&mut self, [pending, mask, source]: [u16; 3]) {
+        self.pending = pending;
+        self.mask = mask;
+        self.source = source;
+    }
+
+    pub fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            PENDING => self.pending | self.asserted,
+            MASK => self.mask,
+            _ => self.source,
+        }
+    }
+
+    pub fn write(&mut self, offset: u16, val: u16) -> Result<(), Error> {
+        match offset {
+            PENDING => self.pending &= !val,
+            MASK => self.mask = val,
+            _ => return Err(Error::memory("the interrupt source register is read only")),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::{interrupts, Device};
+use crate::error::Error;
+
+/*
+ *  Register map, as offsets from the keyboard's base address:
+ *
+ *  0 STATUS    bits 0-11: keys waiting, bit 12: shift held, bit 13: ctrl held, bit 14: alt
+ *              held, bit 15: keys were dropped since STATUS was last read, reading it clears
+ *              the bit
+ *  1 DATA      reading pops the oldest waiting key, or repeats the last one if none are waiting
+ *
+ *  Only key presses are queued. Releases, RELEASED set on the scan code, just let go of a held
+ *  modifier. The keyboard irq stays asserted while keys are waiting.
+ */
+
+pub const STATUS: u16 = 0;
+pub const DATA: u16 = 1;
+
+pub const SHIFT: u16 = 0x1000;
+pub const CTRL: u16 = 0x2000;
+pub const ALT: u16 = 0x4000;
+pub const OVERFLOW: u16 = 0x8000;
+
+/// Set on the scan code of a released key.
+pub const RELEASED: u16 = 0x0100;
+
+pub const DEFAULT_DEPTH: usize = 16;
+/// The most keys STATUS can count.
+pub const MAX_DEPTH: usize = 0x0FFF;
+
+/// Scan codes of the modifier keys, left and right, and their STATUS bit.
+const MODIFIERS: [(u16, u16); 6] = [
+    (0x0012, SHIFT),
+    (0x0059, SHIFT),
+    (0x0014, CTRL),
+    (0xE014, CTRL),
+    (0x0011, ALT),
+    (0xE011, ALT),
+];
+
+/// Keys received from the host, waiting to be read by the cpu. Keys pushed by the host arrive
+/// in the fifo the next time the keyboard is ticked, so they always arrive between two
+/// instructions.
+pub struct KeyFifo {
+    incoming: Vec<u16>,
+    keys: VecDeque<u16>,
+    depth: usize,
+    last: u16,
+    /// Bit n set while modifier n of `MODIFIERS` is held.
+    held: u16,
+    overflow: bool,
+    log: Option<Vec<u16>>,
+}
+
+impl Default for KeyFifo {
+    fn default() -> Self {
+        KeyFifo::new(DEFAULT_DEPTH)
+    }
+}
+
+impl KeyFifo {
+    pub fn new(depth: usize) -> KeyFifo {
+        KeyFifo {
+            incoming: Vec::new(),
+            keys: VecDeque::new(),
+            depth,
+            last: 0,
+            held: 0,
+            overflow: false,
+            log: None,
+        }
+    }
+
+    /// Changes how many keys can wait, dropping the newest ones if more are waiting already.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        self.keys.truncate(depth);
+    }
+
+    /// Sends a key from the host, it arrives with the next tick.
+    pub fn push(&mut self, key: u16) {
+        self.incoming.push(key);
+    }
+
+    /// Queues a key right away, or drops it and flags an overflow if the fifo is full. Releases
+    /// are not queued, they only update the held modifiers.
+    pub fn receive(&mut self, key: u16) {
+        if let Some(log) = &mut self.log {
+            log.push(key);
+        }
+        if let Some(i) = MODIFIERS
+            .iter()
+            .position(|(code, _)| *code == key & !RELEASED)
+        {
+            if key & RELEASED != 0 {
+                self.held &= !(1 << i);
+            } else {
+                self.held |= 1 << i;
+            }
+        }
+        if key & RELEASED != 0 {
+            return;
+        }
+        if self.keys.len() < self.depth {
+            self.keys.push_back(key);
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    fn deliver(&mut self) {
+        for key in std::mem::take(&mut self.incoming) {
+            self.receive(key);
+        }
+    }
+
+    /// The waiting keys, oldest first.
+    pub fn keys(&self) -> impl Iterator<Item = u16> + '_ {
+        self.keys.iter().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The key DATA reads as once the fifo is empty.
+    pub fn last(&self) -> u16 {
+        self.last
+    }
+
+    /// The modifier keys held down, for snapshots.
+    pub fn held(&self) -> u16 {
+        self.held
+    }
+
+    /// Replaces the fifo contents and held modifiers, as restored from a snapshot.
+    pub fn restore(&mut self, last: u16, held: u16, keys: &[u16]) {
+        self.last = last;
+        self.held = held;
+        self.keys = keys.iter().copied().take(self.depth).collect();
+        self.overflow = false;
+    }
+
+    /// Starts recording every key received, including dropped ones, see `take_log`. Does
+    /// nothing if already recording.
+    pub fn log_keys(&mut self) {
+        self.log.get_or_insert_with(Vec::new);
+    }
+
+    /// Returns the keys received since the last call, if logging is on.
+    pub fn take_log(&mut self) -> Vec<u16> {
+        match &mut self.log {
+            Some(log) => std::mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    fn status(&self) -> u16 {
+        let modifiers = MODIFIERS
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.held & (1 << i) != 0)
+            .fold(0, |bits, (_, (_, bit))| bits | bit);
+        let overflow = if self.overflow { OVERFLOW } else { 0 };
+        self.keys.len().min(MAX_DEPTH) as u16 | modifiers | overflow
+    }
+}
+
+/// The keyboard registers at 0xFFFE. Keys are pushed into the fifo by the terminal's key thread,
+/// or received straight away from an input replay.
+pub struct Keyboard {
+    fifo: Arc<Mutex<KeyFifo>>,
+}
+
+impl Keyboard {
+    pub fn new(fifo: Arc<Mutex<KeyFifo>>) -> Keyboard {
+        Keyboard { fifo }
+    }
+}
+
+impl Device for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        let fifo = self.fifo.lock().unwrap();
+        match offset {
+            STATUS => fifo.status(),
+            _ => fifo.keys.front().copied().unwrap_or(fifo.last),
+        }
+    }
+
+    fn read(&mut self, offset: u16) -> Result<u16, Error> {
+        let mut fifo = self.fifo.lock().unwrap();
+        Ok(match offset {
+            STATUS => {
+                let status = fifo.status();
+                fifo.overflow = false;
+                status
+            }
+            _ => {
+                if let Some(key) = fifo.keys.pop_front() {
+                    fifo.last = key;
+                }
+                fifo.last
+            }
+        })
+    }
+
+    fn write(&mut self, _offset: u16, _val: u16) -> Result<(), Error> {
+        Err(Error::memory("the keyboard is read only"))
+    }
+
+    fn tick(&mut self, _instructions: u64) {
+        self.fifo.lock().unwrap().deliver();
+    }
+
+    fn irq_line(&self) -> Option<u16> {
+        Some(interrupts::KEYBOARD)
+    }
+
+    fn irq_asserted(&self) -> bool {
+        !self.fifo.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyboard(keys: &[u16]) -> Keyboard {
+        let mut fifo = KeyFifo::default();
+        keys.iter().for_each(|key| fifo.receive(*key));
+        Keyboard::new(Arc::new(Mutex::new(fifo)))
+    }
+
+    #[test]
+    fn releases_are_not_queued() {
+        let mut keyboard = keyboard(&[0x001C, 0x011C, 0xE075, 0xE175]);
+        assert_eq!(keyboard.read(STATUS).unwrap(), 2);
+        assert_eq!(keyboard.read(DATA).unwrap(), 0x001C);
+        assert_eq!(keyboard.read(DATA).unwrap(), 0xE075);
+        assert_eq!(keyboard.read(DATA).unwrap(), 0xE075);
+    }
+
+    #[test]
+    fn held_modifiers() {
+        // shift a, then a after letting go of shift
+        let mut keyboard = keyboard(&[0x0012, 0x001C, 0x011C]);
+        assert_eq!(keyboard.read(STATUS).unwrap(), SHIFT | 2);
+        keyboard.read(DATA).unwrap();
+        assert_eq!(keyboard.read(DATA).unwrap(), 0x001C);
+        assert_eq!(keyboard.read(STATUS).unwrap(), SHIFT);
+
+        let fifo = Arc::clone(&keyboard.fifo);
+        [0x0112, 0x001C, 0x011C]
+            .iter()
+            .for_each(|key| fifo.lock().unwrap().receive(*key));
+        assert_eq!(keyboard.read(STATUS).unwrap(), 1);
+
+        // either side counts, and the bits stay until both are let go
+        [0x0059, 0xE014, 0x0012, 0x0159]
+            .iter()
+            .for_each(|key| fifo.lock().unwrap().receive(*key));
+        assert_eq!(
+            keyboard.read(STATUS).unwrap() & !MAX_DEPTH as u16,
+            SHIFT | CTRL
+        );
+        [0x0112, 0xE114, 0x0011]
+            .iter()
+            .for_each(|key| fifo.lock().unwrap().receive(*key));
+        assert_eq!(keyboard.read(STATUS).unwrap() & !MAX_DEPTH as u16, ALT);
+    }
+}
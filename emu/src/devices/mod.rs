@@ -0,0 +1,413 @@
+pub mod checks;
+pub mod exceptions;
+pub mod interrupts;
+pub mod keyboard;
+pub mod timer;
+pub mod uart;
+
+use std::sync::{Arc, Mutex, MutexGuard, atomic::{AtomicU16, Ordering}};
+
+use crate::hardware::def::{
+    EXC_BASE, INTC_BASE, IO_START, KEYBOARD_BASE, RAM_SIZE, TIMER_BASE, VGA_HEIGHT, VGA_WIDTH,
+};
+use crate::error::Error;
+use crate::symbols::{Addr, Symbols};
+
+pub use self::checks::Checks;
+pub use self::exceptions::ExceptionUnit;
+pub use self::interrupts::InterruptController;
+pub use self::keyboard::{KeyFifo, Keyboard};
+pub use self::timer::Timer;
+pub use self::uart::Uart;
+
+/// A peripheral mapped into the io window. Offsets are relative to the start of the range the
+/// device was attached at.
+pub trait Device {
+    /// Used in error messages and device listings.
+    fn name(&self) -> &str;
+
+    /// Reads a word without side effects, for debuggers and snapshots.
+    fn peek(&self, offset: u16) -> u16;
+
+    /// Reads a word as the cpu does. Override for devices where reading has side effects, like
+    /// popping a fifo. Errors are as for `write`.
+    fn read(&mut self, offset: u16) -> Result<u16, Error> {
+        Ok(self.peek(offset))
+    }
+
+    /// Writes a word. Writes the device rejects are `Error::memory`, failures of its host side,
+    /// like a closed socket, are `Error::Io`.
+    fn write(&mut self, offset: u16, val: u16) -> Result<(), Error>;
+
+    /// The device's registers and internal state, for snapshots. Devices without state of their
+    /// own keep the default.
+    fn state(&self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    /// Puts back a state returned by `state`.
+    fn restore(&mut self, _state: &[u16]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Lets the device know how many instructions ran since the last call.
+    fn tick(&mut self, _instructions: u64) {}
+
+    /// The interrupt controller line the device raises, see `interrupts` for the lines.
+    fn irq_line(&self) -> Option<u16> {
+        None
+    }
+
+    /// Returns true once for every interrupt the device wants to raise.
+    fn take_irq(&mut self) -> bool {
+        false
+    }
+
+    /// Whether the device holds its irq line asserted, for level triggered devices.
+    fn irq_asserted(&self) -> bool {
+        false
+    }
+}
+
+struct Mapping {
+    start: u16,
+    end: u16,
+    device: Box<dyn Device>,
+}
+
+fn is_intc(addr: u16) -> bool {
+    (INTC_BASE..INTC_BASE + interrupts::SIZE).contains(&addr)
+}
+
+fn is_exc(addr: u16) -> bool {
+    (EXC_BASE..EXC_BASE + exceptions::SIZE).contains(&addr)
+}
+
+/// The error for a failed device access described by `message`: host failures stay `Io`,
+/// anything else is a memory error the program caused.
+fn device_error(e: Error, message: String) -> Error {
+    match e {
+        Error::Io(_) => Error::Io(message),
+        _ => Error::memory(message),
+    }
+}
+
+/// A memory access made through `Devices`, recorded while accesses are being logged.
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+    Read { addr: u16, val: u16 },
+    Write { addr: u16, val: u16 },
+}
+
+/// The memory bus. Rom, vram, ram, the interrupt controller and the exception unit are built in,
+/// everything else in the io window goes to the devices attached there.
+pub struct Devices {
+    rom: Vec<u16>,
+    vram: Arc<Vec<AtomicU16>>,
+    ram: Vec<u16>,
+    key: Arc<Mutex<KeyFifo>>,
+    interrupts: InterruptController,
+    exceptions: ExceptionUnit,
+    io: Vec<Mapping>,
+    accesses: Option<Vec<Access>>,
+    checks: Option<Checks>,
+    symbols: Arc<Symbols>,
+}
+
+impl Devices {
+    pub fn new(rom: Vec<u16>) -> Devices {
+        let key = Arc::new(Mutex::new(KeyFifo::default()));
+        let mut devices = Devices {
+            rom,
+            vram: Arc::new(
+                (0..VGA_WIDTH * VGA_HEIGHT)
+                    .map(|_| AtomicU16::new(0))
+                    .collect(),
+            ),
+            ram: vec![0; RAM_SIZE],
+            key: Arc::clone(&key),
+            interrupts: InterruptController::new(),
+            exceptions: ExceptionUnit::new(),
+            io: Vec::new(),
+            accesses: None,
+            checks: None,
+            symbols: Arc::new(Symbols::default()),
+        };
+        devices
+            .attach(TIMER_BASE, TIMER_BASE + 3, Box::new(Timer::new()))
+            .unwrap();
+        devices
+            .attach(KEYBOARD_BASE, KEYBOARD_BASE + 1, Box::new(Keyboard::new(key)))
+            .unwrap();
+        devices
+    }
+
+    /// Maps `device` onto the inclusive range `start..=end`, which has to lie inside the io
+    /// window and not overlap any other device.
+    pub fn attach(&mut self, start: u16, end: u16, device: Box<dyn Device>) -> Result<(), String> {
+        let name = device.name();
+        if start < IO_START || end < start {
+            return Err(format!(
+                "Can not attach {name} at {start:#06x}..={end:#06x}, it is outside the io window"
+            ));
+        }
+        if start < INTC_BASE + interrupts::SIZE && INTC_BASE <= end {
+            return Err(format!(
+                "Can not attach {name} at {start:#06x}..={end:#06x}, it overlaps the interrupt controller"
+            ));
+        }
+        if start < EXC_BASE + exceptions::SIZE && EXC_BASE <= end {
+            return Err(format!(
+                "Can not attach {name} at {start:#06x}..={end:#06x}, it overlaps the exception unit"
+            ));
+        }
+        if let Some(other) = self.io.iter().find(|m| start <= m.end && m.start <= end) {
+            return Err(format!(
+                "Can not attach {name} at {start:#06x}..={end:#06x}, it overlaps {} at {:#06x}..={:#06x}",
+                other.device.name(),
+                other.start,
+                other.end
+            ));
+        }
+        self.io.push(Mapping { start, end, device });
+        Ok(())
+    }
+
+    /// Names and ranges of the attached devices.
+    pub fn attached(&self) -> impl Iterator<Item = (&str, u16, u16)> {
+        self.io.iter().map(|m| (m.device.name(), m.start, m.end))
+    }
+
+    /// The state of every attached device that has one, by base address.
+    pub fn device_states(&self) -> Vec<(u16, Vec<u16>)> {
+        self.io
+            .iter()
+            .map(|m| (m.start, m.device.state()))
+            .filter(|(_, state)| !state.is_empty())
+            .collect()
+    }
+
+    /// Restores device states returned by `device_states`, the devices have to be attached.
+    pub fn restore_devices(&mut self, states: &[(u16, Vec<u16>)]) -> Result<(), String> {
+        for (start, state) in states {
+            let m = self
+                .io
+                .iter_mut()
+                .find(|m| m.start == *start)
+                .ok_or_else(|| format!("the snapshot has a device at {start:#06x}, nothing is attached there"))?;
+            m.device
+                .restore(state)
+                .map_err(|e| format!("Could not restore {} at {start:#06x}: {e}", m.device.name()))?;
+        }
+        Ok(())
+    }
+
+    /// Advances every device by `instructions` executed instructions.
+    pub fn tick(&mut self, instructions: u64) {
+        for m in &mut self.io {
+            m.device.tick(instructions);
+        }
+    }
+
+    /// Passes a request on `line` to the interrupt controller.
+    pub fn request_irq(&mut self, line: u16) {
+        self.interrupts.request(line);
+    }
+
+    /// Collects interrupt requests from the devices into the controller's pending register.
+    pub fn poll_irqs(&mut self) {
+        for m in &mut self.io {
+            if let Some(line) = m.device.irq_line() {
+                if m.device.take_irq() {
+                    self.interrupts.request(line);
+                }
+                self.interrupts.assert(line, m.device.irq_asserted());
+            }
+        }
+    }
+
+    /// Takes the highest priority unmasked interrupt, see `poll_irqs`.
+    pub fn take_irq(&mut self) -> Option<u16> {
+        self.interrupts.take()
+    }
+
+    pub fn interrupts(&self) -> &InterruptController {
+        &self.interrupts
+    }
+
+    pub fn interrupts_mut(&mut self) -> &mut InterruptController {
+        &mut self.interrupts
+    }
+
+    pub fn exceptions(&self) -> &ExceptionUnit {
+        &self.exceptions
+    }
+
+    pub fn exceptions_mut(&mut self) -> &mut ExceptionUnit {
+        &mut self.exceptions
+    }
+
+    fn mapping(&self, addr: u16) -> Option<&Mapping> {
+        self.io.iter().find(|m| (m.start..=m.end).contains(&addr))
+    }
+
+    /// Split from `self` so errors can borrow the symbols while a device is borrowed mutably.
+    fn mapping_mut(io: &mut [Mapping], addr: u16) -> Option<&mut Mapping> {
+        io.iter_mut().find(|m| (m.start..=m.end).contains(&addr))
+    }
+
+    /// Starts recording every successful read and write, see `take_accesses`.
+    pub fn log_accesses(&mut self) {
+        self.accesses = Some(Vec::new());
+    }
+
+    /// Returns the accesses recorded since the last call, if logging is on.
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        match &mut self.accesses {
+            Some(log) => std::mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    fn record(&mut self, access: Access) {
+        if let Some(log) = &mut self.accesses {
+            log.push(access);
+        }
+    }
+
+    /// Turns on the checked mode, data reads and writes through the bus are checked from now on.
+    pub fn enable_checks(&mut self, checks: Checks) {
+        self.checks = Some(checks);
+    }
+
+    pub fn checks(&self) -> Option<&Checks> {
+        self.checks.as_ref()
+    }
+
+    pub fn checks_mut(&mut self) -> Option<&mut Checks> {
+        self.checks.as_mut()
+    }
+
+    /// Names the addresses in errors and for the front ends.
+    pub fn set_symbols(&mut self, symbols: Arc<Symbols>) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// The symbol table, for reports written once the cpu is gone.
+    pub fn symbols_handle(&self) -> Arc<Symbols> {
+        Arc::clone(&self.symbols)
+    }
+
+    pub fn vram(&self) -> &[AtomicU16] {
+        &self.vram
+    }
+
+    /// The vram, for displays running on other threads.
+    pub fn vram_handle(&self) -> Arc<Vec<AtomicU16>> {
+        Arc::clone(&self.vram)
+    }
+
+    /// The keyboard fifo, for keyboards running on other threads.
+    pub fn key_handle(&self) -> Arc<Mutex<KeyFifo>> {
+        Arc::clone(&self.key)
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.ram
+    }
+
+    pub fn key_fifo(&self) -> MutexGuard<'_, KeyFifo> {
+        self.key.lock().unwrap()
+    }
+
+    /// Reads a word without triggering any device side effects.
+    pub fn peek(&self, addr: u16) -> Result<u16, Error> {
+        match addr {
+            0..=0x7FFF => Ok(self.rom[addr as usize]),
+            0x8000..=0xBFFF => Ok(self.ram[(addr - 0x8000) as usize]),
+            _ if is_intc(addr) => Ok(self.interrupts.peek(addr - INTC_BASE)),
+            _ if is_exc(addr) => Ok(self.exceptions.peek(addr - EXC_BASE)),
+            _ => match self.mapping(addr) {
+                Some(m) => Ok(m.device.peek(addr - m.start)),
+                None => Err(Error::unmapped(addr, None)),
+            },
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) -> Result<u16, Error> {
+        if let Some(checks) = &self.checks {
+            checks.read(addr, &self.symbols).map_err(Error::memory)?;
+        }
+        self.fetch(addr)
+    }
+
+    /// Reads an instruction word, the same as `read` except that the checked mode ignores it.
+    pub fn fetch(&mut self, addr: u16) -> Result<u16, Error> {
+        let val = match addr {
+            _ if addr <= 0xBFFF || is_intc(addr) || is_exc(addr) => self.peek(addr)?,
+            _ => match Devices::mapping_mut(&mut self.io, addr) {
+                Some(m) => m.device.read(addr - m.start).map_err(|e| {
+                    let message = format!(
+                        "Memory location {} ({}): {e}",
+                        Addr(addr, &self.symbols),
+                        m.device.name()
+                    );
+                    device_error(e, message)
+                })?,
+                None => return Err(Error::unmapped(addr, None)),
+            },
+        };
+        self.record(Access::Read { addr, val });
+        Ok(val)
+    }
+
+    pub fn write(&mut self, addr: u16, val: u16) -> Result<(), Error> {
+        match addr {
+            0..=0x7FFF => match self.vram.get(addr as usize) {
+                Some(cell) => {
+                    cell.swap(val, Ordering::Relaxed);
+                }
+                None => return Err(Error::unmapped(addr, Some(val))),
+            },
+            0x8000..=0xBFFF => self.ram[(addr - 0x8000) as usize] = val,
+            _ if is_intc(addr) => self
+                .interrupts
+                .write(addr - INTC_BASE, val)
+                .map_err(|e| {
+                    let message = format!("Memory location {}={val:#06x}: {e}", Addr(addr, &self.symbols));
+                    device_error(e, message)
+                })?,
+            _ if is_exc(addr) => self
+                .exceptions
+                .write(addr - EXC_BASE, val)
+                .map_err(|e| {
+                    let message = format!("Memory location {}={val:#06x}: {e}", Addr(addr, &self.symbols));
+                    device_error(e, message)
+                })?,
+            _ => match Devices::mapping_mut(&mut self.io, addr) {
+                Some(m) => m.device.write(addr - m.start, val).map_err(|e| {
+                    let message = format!(
+                        "Memory location {}={val:#06x} ({}): {e}",
+                        Addr(addr, &self.symbols),
+                        m.device.name()
+                    );
+                    device_error(e, message)
+                })?,
+                None => return Err(Error::unmapped(addr, Some(val))),
+            },
+        }
+        if let Some(checks) = &mut self.checks {
+            checks.write(addr);
+        }
+        self.record(Access::Write { addr, val });
+        Ok(())
+    }
+}
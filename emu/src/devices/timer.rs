@@ -0,0 +1,134 @@
+use super::{interrupts, Device};
+use crate::error::Error;
+
+/*
+ *  Register map, as offsets from the timer's base address:
+ *
+ *  0 CONTROL   bit 0: enable, bit 1: raise an irq on expiry, bit 2: periodic
+ *  1 RELOAD    value loaded into COUNTER when the timer is enabled, and on every periodic expiry
+ *  2 COUNTER   instructions left until expiry, counts down while enabled
+ *  3 STATUS    bit 0: expired since STATUS was last read, reading it clears the bit
+ *
+ *  A one shot timer clears its enable bit when it expires. A reload of 0 never expires.
+ */
+
+pub const CONTROL: u16 = 0;
+pub const RELOAD: u16 = 1;
+pub const COUNTER: u16 = 2;
+pub const STATUS: u16 = 3;
+
+const ENABLE: u16 = 0x1;
+const IRQ_ENABLE: u16 = 0x2;
+const PERIODIC: u16 = 0x4;
+
+/// Interval timer counting executed instructions.
+#[derive(Default)]
+pub struct Timer {
+    control: u16,
+    reload: u16,
+    counter: u16,
+    expired: bool,
+    irq: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+
+    fn expire(&mut self) {
+        self.expired = true;
+        if self.control & IRQ_ENABLE != 0 {
+            self.irq = true;
+        }
+        if self.control & PERIODIC != 0 {
+            self.counter = self.reload;
+        } else {
+            self.control &= !ENABLE;
+        }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            CONTROL => self.control,
+            RELOAD => self.reload,
+            COUNTER => self.counter,
+            _ => u16::from(self.expired),
+        }
+    }
+
+    fn read(&mut self, offset: u16) -> Result<u16, Error> {
+        let val = self.peek(offset);
+        if offset == STATUS {
+            self.expired = false;
+        }
+        Ok(val)
+    }
+
+    fn write(&mut self, offset: u16, val: u16) -> Result<(), Error> {
+        match offset {
+            CONTROL => {
+                if self.control & ENABLE == 0 && val & ENABLE != 0 {
+                    self.counter = self.reload;
+                }
+                self.control = val & (ENABLE | IRQ_ENABLE | PERIODIC);
+            }
+            RELOAD => self.reload = val,
+            COUNTER => self.counter = val,
+            _ => return Err(Error::memory("the timer status register is read only")),
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Vec<u16> {
+        vec![
+            self.control,
+            self.reload,
+            self.counter,
+            u16::from(self.expired),
+            u16::from(self.irq),
+        ]
+    }
+
+    fn restore(&mut self, state: &[u16]) -> Result<(), String> {
+        let [control, reload, counter, expired, irq] = state else {
+            return Err(format!(
+                "expected 5 words of timer state, got {}",
+                state.len()
+            ));
+        };
+        self.control = *control;
+        self.reload = *reload;
+        self.counter = *counter;
+        self.expired = *expired != 0;
+        self.irq = *irq != 0;
+        Ok(())
+    }
+
+    fn tick(&mut self, instructions: u64) {
+        let mut left = instructions;
+        while self.control & ENABLE != 0 && self.counter != 0 {
+            if left < self.counter as u64 {
+                self.counter -= left as u16;
+                return;
+            }
+            left -= self.counter as u64;
+            self.counter = 0;
+            self.expire();
+        }
+    }
+
+    fn irq_line(&self) -> Option<u16> {
+        Some(interrupts::TIMER)
+    }
+
+    fn take_irq(&mut self) -> bool {
+        std::mem::take(&mut self.irq)
+    }
+}
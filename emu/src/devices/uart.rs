@@ -0,0 +1,205 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::{interrupts, Device};
+use crate::error::Error;
+
+/*
+ *  Register map, as offsets from the uart's base address:
+ *
+ *  0 DATA      reading pops the oldest received byte (0 if none), writing sends the low byte
+ *  1 STATUS    bit 0: a received byte is waiting, bit 1: ready to send (always set), read only
+ *  2 CONTROL   bit 0: raise an irq when bytes are received
+ */
+
+pub const DATA: u16 = 0;
+pub const STATUS: u16 = 1;
+pub const CONTROL: u16 = 2;
+
+/// Number of registers, the uart takes up `base..base + SIZE`.
+pub const SIZE: u16 = 3;
+
+pub const RX_READY: u16 = 1;
+pub const TX_READY: u16 = 2;
+
+pub const RX_IRQ: u16 = 1;
+
+/// A serial port whose other end is a host byte stream.
+pub struct Uart {
+    rx: Arc<Mutex<VecDeque<u8>>>,
+    received: Arc<AtomicBool>,
+    /// Why receiving from the host stopped, if it failed rather than reaching end of file.
+    failed: Arc<Mutex<Option<String>>>,
+    tx: Box<dyn Write>,
+    control: u16,
+    irq: bool,
+}
+
+impl Uart {
+    /// Sends written bytes to `tx`, and receives everything read from `rx` on a background
+    /// thread until it hits end of file or an error. An error is reported by the first read of
+    /// DATA after the bytes received before it are used up.
+    pub fn new<R: Read + Send + 'static>(mut rx: R, tx: Box<dyn Write>) -> Uart {
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let received = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(Mutex::new(None));
+
+        let (thread_queue, thread_received, thread_failed) = (
+            Arc::clone(&queue),
+            Arc::clone(&received),
+            Arc::clone(&failed),
+        );
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            loop {
+                match rx.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        thread_queue.lock().unwrap().extend(&buf[..n]);
+                        thread_received.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        *thread_failed.lock().unwrap() = Some(e.to_string());
+                        break;
+                    }
+                }
+            }
+        });
+
+        Uart {
+            rx: queue,
+            received,
+            failed,
+            tx,
+            control: 0,
+            irq: false,
+        }
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        let rx = self.rx.lock().unwrap();
+        match offset {
+            DATA => rx.front().copied().unwrap_or(0) as u16,
+            STATUS => TX_READY | if rx.is_empty() { 0 } else { RX_READY },
+            _ => self.control,
+        }
+    }
+
+    fn read(&mut self, offset: u16) -> Result<u16, Error> {
+        if offset != DATA {
+            return Ok(self.peek(offset));
+        }
+        if let Some(b) = self.rx.lock().unwrap().pop_front() {
+            return Ok(b as u16);
+        }
+        match self.failed.lock().unwrap().take() {
+            Some(e) => Err(Error::Io(format!("could not receive from the host: {e}"))),
+            None => Ok(0),
+        }
+    }
+
+    fn write(&mut self, offset: u16, val: u16) -> Result<(), Error> {
+        match offset {
+            DATA => self
+                .tx
+                .write_all(&[val as u8])
+                .and_then(|_| self.tx.flush())
+                .map_err(|e| Error::Io(format!("could not send to the host: {e}")))?,
+            STATUS => return Err(Error::memory("the uart status register is read only")),
+            _ => self.control = val,
+        }
+        Ok(())
+    }
+
+    /// Control, the pending irq and the received bytes the program has not read yet.
+    fn state(&self) -> Vec<u16> {
+        let rx = self.rx.lock().unwrap();
+        [self.control, u16::from(self.irq)]
+            .into_iter()
+            .chain(rx.iter().map(|b| *b as u16))
+            .collect()
+    }
+
+    fn restore(&mut self, state: &[u16]) -> Result<(), String> {
+        let [control, irq, bytes @ ..] = state else {
+            return Err(format!(
+                "expected at least 2 words of uart state, got {}",
+                state.len()
+            ));
+        };
+        self.control = *control;
+        self.irq = *irq != 0;
+        *self.rx.lock().unwrap() = bytes.iter().map(|b| *b as u8).collect();
+        Ok(())
+    }
+
+    fn tick(&mut self, _instructions: u64) {
+        if self.received.swap(false, Ordering::Relaxed) && self.control & RX_IRQ != 0 {
+            self.irq = true;
+        }
+    }
+
+    fn irq_line(&self) -> Option<u16> {
+        Some(interrupts::UART)
+    }
+
+    fn take_irq(&mut self) -> bool {
+        std::mem::take(&mut self.irq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// Gives its bytes once, then fails.
+    struct Failing(&'static [u8]);
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::other("the line dropped"));
+            }
+            let n = self.0.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    /// Reads DATA until it gives something other than an empty queue's 0.
+    fn read_data(uart: &mut Uart) -> Result<u16, Error> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match uart.read(DATA) {
+                Ok(0) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn host_read_failures_are_io_errors() {
+        let mut uart = Uart::new(Failing(b"hi"), Box::new(io::sink()));
+        assert_eq!(read_data(&mut uart).unwrap(), b'h' as u16);
+        assert_eq!(read_data(&mut uart).unwrap(), b'i' as u16);
+        match read_data(&mut uart) {
+            Err(Error::Io(message)) => assert!(message.contains("the line dropped"), "{message}"),
+            other => panic!("expected an io error, got {other:?}"),
+        }
+        assert_eq!(uart.read(DATA).unwrap(), 0);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::hardware::def::*;
+use crate::hardware::register::REGISTER_NAMES;
+
+const ALU_NAMES: [&str; 15] = [
+    "not", "and", "or", "xor", "add", "sub", "mov", "cmp", "shr", "sshr", "shl", "mul", "mulh",
+    "div", "mod",
+];
+
+/// The assembler's immediate forms, it has none for not and mov.
+const IALU_NAMES: [Option<&str>; 15] = [
+    None,
+    Some("iand"),
+    Some("ior"),
+    Some("ixor"),
+    Some("iadd"),
+    Some("isub"),
+    None,
+    Some("icmp"),
+    Some("ishr"),
+    Some("isshr"),
+    Some("ishl"),
+    Some("imul"),
+    Some("imulh"),
+    Some("idiv"),
+    Some("imod"),
+];
+
+const JUMP_NAMES: [&str; 5] = ["jmp", "jz", "jnz", "jn", "jp"];
+
+const SP_INDEX: u16 = 13;
+
+fn reg(r: u16) -> &'static str {
+    REGISTER_NAMES[r as usize]
+}
+
+fn invalid(inst: u16) -> String {
+    format!("invalid {inst:#06x}")
+}
+
+/// Turns an instruction word back into assembler syntax, using register aliases.
+pub fn disassemble(inst: u16) -> String {
+    let opcode: u16 = (inst & 0xF000) >> 12;
+    let r1: u16 = (inst & 0x0F00) >> 8;
+    let r2: u16 = (inst & 0x00F0) >> 4;
+    let imm8: u16 = inst & 0x00FF;
+    let low: u16 = inst & 0x000F;
+
+    match opcode {
+        LOAD | STR => {
+            let name = if opcode == LOAD { "load" } else { "str" };
+            if low == 0 {
+                format!("{name} {} {}", reg(r1), reg(r2))
+            } else {
+                format!("{name} {} {} {low}", reg(r1), reg(r2))
+            }
+        }
+        IMOV => format!("imov {} {imm8:#04x}", reg(r1)),
+        IMOH => format!("imoh {} {imm8:#04x}", reg(r1)),
+        PUSH if r1 == SP_INDEX => format!("push {}", reg(r2)),
+        PUSH => format!("push {} {}", reg(r1), reg(r2)),
+        POP if r2 == SP_INDEX => format!("pop {}", reg(r1)),
+        POP => format!("pop {} {}", reg(r1), reg(r2)),
+        HALT => "halt".to_string(),
+        RTI => "rti".to_string(),
+        ALU => match ALU_NAMES.get(low as usize) {
+            Some(&"not") => format!("not {}", reg(r1)),
+            Some(name) => format!("{name} {} {}", reg(r1), reg(r2)),
+            None => invalid(inst),
+        },
+        IALU => match IALU_NAMES.get(low as usize) {
+            Some(Some("icmp")) if r2 == 0 => format!("tst {}", reg(r1)),
+            Some(Some(name)) => format!("{name} {} {r2}", reg(r1)),
+            // the cpu runs these, but there is no way to write them
+            Some(None) => format!("{inst:#06x}"),
+            None => invalid(inst),
+        },
+        JMP => {
+            let name = match JUMP_NAMES.get(low as usize) {
+                Some(name) => name,
+                None => return invalid(inst),
+            };
+            // the r bit wins over the l bit, and ignores the destination register
+            if r2 & 2 != 0 {
+                format!("{name}r")
+            } else if r2 & 1 != 0 {
+                format!("{name}l {}", reg(r1))
+            } else {
+                format!("{name} {}", reg(r1))
+            }
+        }
+        _ => invalid(inst),
+    }
+}
@@ -1,71 +1,114 @@
-use std::io::stdout;
-use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::fs::File;
+use std::io::{stdout, Write};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use crossterm::cursor::Show;
 use crossterm::execute;
 use crossterm::terminal::disable_raw_mode;
 
-use crate::devices::Devices;
-use crate::hardware::def::*;
-use crate::hardware::key::Key;
-use crate::hardware::register::{Registers, StatusRegister, StatusRegisterFlag};
-use crate::hardware::vga::Vga;
-
-fn bit(n: i32, bit: u8) -> bool {
-    (n >> bit) & 1 != 0
-}
-
-fn alu(op: u16, a: i32, b: i32, sr: &mut StatusRegister) -> u16 {
-    let agg: i32 = match op {
-        0x0 => !a,
-        0x1 => a & b,
-        0x2 => a | b,
-        0x3 => a ^ b,
-        0x4 => a + b,
-        0x5 => a - b,
-        0x6 => b,
-        0x7 => a - b,
-        0x8 => ((a as u16) >> b) as i32,
-        0x9 => ((a as i16) >> b) as i32,
-        0xA => a << b,
-        _ => panic!("Invalid alu operation {op}"),
+use emu::devices::{keyboard, Checks};
+use emu::hardware::def::{VGA_HEIGHT, VGA_WIDTH};
+use emu::hardware::key::Key;
+use emu::hardware::vga::Vga;
+use emu::loader::Program;
+use emu::screen::cast::Cast;
+use emu::screen::dump::dump;
+use emu::screen::raster::save_png;
+use emu::snapshot::Snapshot;
+use emu::symbols::Symbols;
+use emu::{Cpu, Error, Step};
+
+use crate::args::Options;
+use crate::expect::Expectations;
+use crate::frames::Frames;
+use crate::input::Input;
+use crate::profile::Profiler;
+use crate::serial;
+use crate::trace::{parse_addr, Tracer};
+
+fn dump_screen(vram: &[AtomicU16], opts: &Options) -> Result<(), Error> {
+    let mut out: Box<dyn Write> = if opts.dump_file == "-" {
+        Box::new(stdout())
+    } else {
+        Box::new(File::create(&opts.dump_file).map_err(|e| {
+            Error::Io(format!("Could not create screen dump file {}: {e}", opts.dump_file))
+        })?)
     };
 
-    sr.set(StatusRegisterFlag::N, agg & 0x8000 != 0);
-    sr.set(StatusRegisterFlag::Z, agg == 0);
-    sr.set(StatusRegisterFlag::X, agg == 0xFFFF);
+    dump(vram, opts.dump_format, &mut out)
+        .and_then(|_| out.flush())
+        .map_err(|e| Error::Io(format!("Could not write screen dump: {e}")))
+}
 
-    match op {
-        0x4 => {
-            sr.set(
-                StatusRegisterFlag::V,
-                (bit(a, 15) == bit(b, 15)) && (bit(a, 15) ^ bit(agg, 15)),
-            );
-            sr.set(StatusRegisterFlag::C, bit(agg, 16));
-        }
-        0x5 | 0x7 => {
-            sr.set(
-                StatusRegisterFlag::V,
-                (bit(a, 15) ^ bit(b, 15)) && (bit(a, 15) != bit(agg, 15)),
-            );
-            sr.set(StatusRegisterFlag::C, bit(agg, 16));
-        }
-        _ => (),
+/// The `--stack-limit`, by default the end of the globals if the symbols say where they are.
+fn stack_limit(opts: &Options, symbols: &Symbols) -> Result<u16, Error> {
+    if !opts.stack_limit.is_empty() {
+        return parse_addr(&opts.stack_limit)
+            .map_err(|e| Error::Usage(format!("Bad --stack-limit: {e}")));
     }
-
-    (agg & 0xFFFF) as u16
+    Ok(symbols.data_end().unwrap_or(0x8000))
 }
 
-#[allow(unused_variables)]
-pub fn emulate(rom: Vec<u16>) -> Result<(), String> {
-    let ram: Vec<u16> = vec![0; RAM_SIZE];
+/// Sets up the VGA display and keyboard threads, hands the cpu to `core`, and tears the
+/// terminal down again once `core` returns. In headless, debug and replay mode no threads are
+/// started, and in headless mode the screen is dumped instead once the program halts. The
+/// machine starts from `--load-state` if given, and is saved to `--save-state` once `core`
+/// returns cleanly, and the screen is rendered to `--screenshot` alongside. A uart is attached if
+/// `--uart` is given, and the display is recorded to `--cast` if given. With `--check` the bus
+/// runs in checked mode. `--expect` runs without a display, like headless mode without the dump.
+/// Addresses are named from `symbols`.
+pub fn run<F>(program: Program, opts: &Options, symbols: &Arc<Symbols>, core: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut Cpu) -> Result<(), Error>,
+{
+    let display =
+        !opts.headless && !opts.debug && opts.replay.is_empty() && opts.expect.is_empty();
+    if !display && !opts.cast.is_empty() {
+        return Err(Error::Usage("--cast records the terminal display, which is off with --headless, --debug, --replay and --expect".to_string()));
+    }
+
+    let mut cpu = Cpu::new(program.rom);
+    cpu.mem.ram_mut().copy_from_slice(&program.ram);
+    cpu.mem.set_symbols(Arc::clone(symbols));
+    if opts.check {
+        let limit = stack_limit(opts, symbols)?;
+        cpu.mem.enable_checks(Checks::new(limit, program.loaded, program.rom_loaded));
+    }
+    if opts.key_fifo == 0 || opts.key_fifo > keyboard::MAX_DEPTH {
+        return Err(Error::Usage(format!(
+            "--key-fifo holds between 1 and {} keys",
+            keyboard::MAX_DEPTH
+        )));
+    }
+    cpu.mem.key_fifo().set_depth(opts.key_fifo);
+    serial::attach(&mut cpu, opts)?;
+    if !opts.load_state.is_empty() {
+        Snapshot::load(&opts.load_state)?.restore(&mut cpu)?;
+    }
 
-    let vram: Vec<AtomicU16> = (0..6000).map(|_| AtomicU16::new(0)).collect();
+    let save = |cpu: &Cpu| -> Result<(), Error> {
+        if !opts.screenshot.is_empty() {
+            save_png(cpu.mem.vram(), &opts.screenshot).map_err(Error::Io)?;
+        }
+        if opts.save_state.is_empty() {
+            return Ok(());
+        }
+        Snapshot::capture(cpu).save(&opts.save_state)
+    };
 
-    let running_count = AtomicU64::new(0);
+    if !display {
+        core(&mut cpu)?;
+        save(&cpu)?;
+        if opts.headless {
+            dump_screen(cpu.mem.vram(), opts)?;
+        }
+        return Ok(());
+    }
 
+    let vram = cpu.mem.vram_handle();
+    let running_count = cpu.count_handle();
     let mut disp_vga: Vga = Vga::new(
         VGA_WIDTH,
         VGA_HEIGHT,
@@ -74,199 +117,116 @@ pub fn emulate(rom: Vec<u16>) -> Result<(), String> {
         Duration::new(0, 100_000_000),
         &running_count,
     );
+    if !opts.cast.is_empty() {
+        disp_vga.record(Cast::create(&opts.cast).map_err(Error::Io)?);
+    }
     disp_vga.reset();
 
-    let key: Arc<Mutex<u16>> = Arc::new(Mutex::new(0));
-    let irq: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
-
-    // INIT REGISTERS
-    let mut registers: Registers = Registers {
-        general: [0; 12],
-        isr: 0,
-        sp: 0xBFFF,
-        sr: StatusRegister { sr: 0 },
-        pc: 0x0000,
-    };
-
-    let mut halt: bool = false;
-
-    let mut key_handler = Key::new(Arc::clone(&irq), Arc::clone(&key));
+    let mut key_handler = Key::new(cpu.mem.key_handle());
 
     let term = AtomicBool::new(false);
     let term1 = &term;
     let term2 = &term;
 
-    std::thread::scope(|scope| -> Result<(), String> {
+    let result = std::thread::scope(|scope| -> Result<(), Error> {
         // start keyboard thread
         let key_handler_thread = scope.spawn(move || key_handler.handle(term1));
         // start vga thread
         let display_thread = scope.spawn(move || disp_vga.start_loop(term2));
 
         // main thread
-        let mut mem = Devices::new(rom, &vram, ram, key);
-        while !halt {
-            if *irq.lock().unwrap() {
-                *irq.lock().unwrap() = false;
-                registers.sp -= 1;
-                mem.write(registers.sp, registers.pc).map_err(|e| {
-                    format!(
-                        "Issue when jumping to isr storing stack pointer instruction pc={:#06x}: {e}",
-                        registers.pc
-                    )
-                })?;
-                registers.sp -= 1;
-                mem.write(registers.sp, registers.sr.sr).map_err(|e| {
-                    format!(
-                        "Issue when jumping to isr storing status register instruction pc={:#06x}: {e}",
-                        registers.pc
-                    )
-                })?;
-                registers.pc = registers.isr;
-                continue;
-            }
-
-            let inst: u16 = mem.read(registers.pc).map_err(|e| {
-                format!("Issue when read instruction pc={:#06x}: {e}", registers.pc)
-            })?;
-            let opcode: u16 = (inst & 0xF000) >> 12;
-
-            let r1: u16 = (inst & 0x0F00) >> 8;
-            let r2: u16 = (inst & 0x00F0) >> 4;
-            let imoh_imm8: u16 = (inst & 0x00FF) << 8;
-            let imov_imm8: u16 =
-                (inst & 0x00FF) | (if (inst & 0x0080) == 0 { 0x0000 } else { 0xFF00 });
-
-            let alu_imm4: u16 = r2;
-            let alu_op: u16 = inst & 0x000F;
-            let load_offset: u16 = alu_op;
-            let jmp_op: u16 = alu_op;
-
-            match opcode {
-                LOAD => {
-                    registers[r1] = mem.read(registers[r2] + load_offset).map_err(|e| {
-                        format!("Issue when executing load at pc={:#06x}: {e}", registers.pc)
-                    })?;
-                }
-                STR => {
-                    mem.write(registers[r1] + load_offset, registers[r2])
-                        .map_err(|e| {
-                            format!(
-                                "Issue when executing store at pc={:#06x}: {e}",
-                                registers.pc
-                            )
-                        })?;
-                }
-                IMOV => {
-                    registers[r1] = imov_imm8;
-                }
-                IMOH => {
-                    registers[r1] = imoh_imm8 | (registers[r1] & 0x00FF);
-                }
-                PUSH => {
-                    registers[r1] -= 1;
-                    mem.write(registers[r1], registers[r2]).map_err(|e| {
-                        format!("Issue when executing push at pc={:#06x}: {e}", registers.pc)
-                    })?;
-                }
-                POP => {
-                    registers[r1] = mem.read(registers[r2]).map_err(|e| {
-                        format!("Issue when executing pop at pc={:#06x}: {e}", registers.pc)
-                    })?;
-                    registers[r2] += 1;
-                }
-                HALT => {
-                    halt = true;
-                }
-                ALU => {
-                    let agg = alu(
-                        alu_op,
-                        registers[r1] as i32,
-                        registers[r2] as i32,
-                        &mut registers.sr,
-                    );
-
-                    if alu_op != 0x7 {
-                        registers[r1] = agg;
-                    }
-                }
-                IALU => {
-                    let agg = alu(
-                        alu_op,
-                        registers[r1] as i32,
-                        alu_imm4 as i32,
-                        &mut registers.sr,
-                    );
-
-                    if alu_op != 0x7 {
-                        registers[r1] = agg;
-                    }
-                }
-                JMP => {
-                    let l: bool = r2 & 1 != 0;
-                    let r: bool = r2 & 2 != 0;
-
-                    let do_jump: bool = match jmp_op {
-                        0 => true,
-                        1 => registers.sr.get(StatusRegisterFlag::Z),
-                        2 => !registers.sr.get(StatusRegisterFlag::Z),
-                        3 => registers.sr.get(StatusRegisterFlag::N),
-                        4 => {
-                            !registers.sr.get(StatusRegisterFlag::Z)
-                                && !registers.sr.get(StatusRegisterFlag::N)
-                        }
-                        _ => false,
-                    };
-
-                    if do_jump {
-                        if r {
-                            registers.pc = mem.read(registers.sp).map_err(|e| {
-                                format!(
-                                    "Issue when executing jump at pc={:#06x}: {e}",
-                                    registers.pc
-                                )
-                            })?;
-                            registers.sp += 1;
-                        } else if l {
-                            registers.sp -= 1;
-                            mem.write(registers.sp, registers.pc + 1).map_err(|e| {
-                                format!(
-                                    "Issue when executing jump and link at pc={:#06x}: {e}",
-                                    registers.pc
-                                )
-                            })?;
-                            registers.pc = registers[r1];
-                        } else {
-                            registers.pc = registers[r1];
-                        }
-                        registers.pc -= 1;
-                    }
-                }
-                RTI => {
-                    registers.sr.sr = mem.read(registers.sp)
-                        .map_err(|e| format!("Issue when executing rti and popping the status register at pc={:#06x}: {e}", registers.pc))?;
-                    registers.sp += 1;
-                    registers.pc = mem.read(registers.sp)
-                        .map_err(|e| format!("Issue when executing rti and popping the return address at pc={:#06x}: {e}", registers.pc))?;
-                    registers.sp += 1;
-                    registers.pc -= 1;
-                }
-                _ => (),
-            }
-            registers.pc += 1;
-            running_count.fetch_add(1, Ordering::Relaxed);
-        }
-
-        let last_pc = registers.pc - 1;
+        let result = core(&mut cpu);
 
         term.swap(true, Ordering::Relaxed);
 
         key_handler_thread.join().unwrap();
         display_thread.join().unwrap();
 
-        Ok(())
-    })?;
+        result
+    });
 
     execute!(stdout(), Show).unwrap();
     disable_raw_mode().unwrap();
-    Ok(())
+    result?;
+    save(&cpu)
+}
+
+/// Runs the program in the interpreter. With `--expect` the expectations are checked once it
+/// stops.
+pub fn emulate(program: Program, opts: &Options, symbols: &Arc<Symbols>) -> Result<(), Error> {
+    let expectations = Expectations::from_options(opts, symbols)?;
+    let limit = match &expectations {
+        Some(expectations) => Some(expectations.limit(opts)),
+        None => (opts.stop_after > 0).then_some(opts.stop_after),
+    };
+    let finish = |cpu: &mut Cpu, result: Result<Step, Error>| match &expectations {
+        Some(expectations) => expectations.check(cpu, result),
+        None => result.map(|_| ()),
+    };
+    let input = Input::from_options(opts)?;
+    let frames = Frames::from_options(opts)?;
+    let mut profiler = Profiler::from_options(opts, symbols)?;
+    let profiling = profiler.as_mut();
+    let tracer = Tracer::from_options(opts)?;
+    let result = run(program, opts, symbols, |cpu| {
+        let result = interpret(cpu, limit, input, frames, profiling, tracer);
+        finish(cpu, result)
+    });
+    // a profile of a failed run still shows where it spent its time
+    if let Some(profiler) = &mut profiler {
+        profiler.finish()?;
+    }
+    result
+}
+
+/// The interpreter loop, logging every step to `tracer` if there is one. Returns the last step
+/// taken, `Step::Halt` if the program halted and anything else if it reached `limit`. The trace
+/// is finished however the loop stops.
+fn interpret(
+    cpu: &mut Cpu,
+    limit: Option<u64>,
+    mut input: Input,
+    mut frames: Option<Frames>,
+    mut profiler: Option<&mut Profiler>,
+    mut tracer: Option<Tracer>,
+) -> Result<Step, Error> {
+    if let Some(tracer) = &mut tracer {
+        tracer.start(cpu);
+    }
+    let mut steps = || -> Result<Step, Error> {
+        let mut last = Step::Instruction;
+        loop {
+            if limit.is_some_and(|limit| cpu.count() >= limit) {
+                return Ok(last);
+            }
+            input.before_step(cpu);
+            if let Some(profiler) = &mut profiler {
+                profiler.before_step(cpu);
+            }
+            if let Some(tracer) = &mut tracer {
+                tracer.before_step(cpu);
+            }
+            let result = cpu.step();
+            if let Some(tracer) = &mut tracer {
+                tracer.after_step(cpu, &result)?;
+            }
+            let result = result?;
+            input.after_step(cpu)?;
+            if let Some(profiler) = &mut profiler {
+                profiler.after_step(cpu, result);
+            }
+            if let Some(frames) = &mut frames {
+                frames.after_step(cpu)?;
+            }
+            if result == Step::Halt {
+                return Ok(result);
+            }
+            last = result;
+        }
+    };
+    let result = steps();
+    if let Some(tracer) = &mut tracer {
+        tracer.finish(result.is_err())?;
+    }
+    result
 }
@@ -0,0 +1,236 @@
+//! Errors that stop the emulator, and the process exit codes they map to.
+
+use std::fmt::{Display, Formatter};
+
+use crate::disasm::disassemble;
+use crate::hardware::register::REGISTER_NAMES;
+use crate::symbols::{Addr, Symbols};
+
+/*
+ *  Exit codes of the emu binary:
+ *
+ *  0  the program halted, or ran for --stop-after instructions
+ *  1  a failure on the host: reading or writing files, the terminal, sockets
+ *  2  bad command line arguments
+ *  3  a memory access the bus could not serve, or one failing --check
+ *  4  an illegal instruction
+ *  5  an illegal alu operation
+ *  6  the machine did not meet the --expect expectations
+ */
+
+/// The cpu at the moment an error stopped it.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    pub pc: u16,
+    /// The instruction at pc, none if the error came before it was fetched.
+    pub inst: Option<u16>,
+    pub registers: [u16; 16],
+}
+
+/// An error or fault printed with the addresses in it named from a symbol table, see
+/// `Error::display`.
+pub struct Symbolic<'a, T> {
+    value: &'a T,
+    symbols: &'a Symbols,
+}
+
+impl Fault {
+    pub fn display<'a>(&'a self, symbols: &'a Symbols) -> Symbolic<'a, Fault> {
+        Symbolic {
+            value: self,
+            symbols,
+        }
+    }
+
+    /// The registers, eight to a line.
+    pub fn registers(&self) -> String {
+        let lines: Vec<String> = self
+            .registers
+            .chunks(8)
+            .enumerate()
+            .map(|(line, values)| {
+                let regs: Vec<String> = values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, val)| format!("{:>3}={val:04x}", REGISTER_NAMES[line * 8 + i]))
+                    .collect();
+                regs.join(" ")
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.display(&Symbols::EMPTY).fmt(f)
+    }
+}
+
+impl Display for Symbolic<'_, Fault> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pc={}", Addr(self.value.pc, self.symbols))?;
+        match self.value.inst {
+            Some(inst) => write!(f, " ({inst:04x}  {})", disassemble(inst)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// A read or write the memory bus could not serve, or one failing a check in checked mode.
+    /// Errors straight from the bus have no fault yet, the cpu adds it.
+    Memory {
+        message: String,
+        fault: Option<Fault>,
+    },
+    /// A read or write of an address nothing is mapped at, `val` is the value of a write.
+    Unmapped {
+        addr: u16,
+        val: Option<u16>,
+        fault: Option<Fault>,
+    },
+    /// An opcode no instruction is defined for.
+    IllegalInstruction { fault: Fault },
+    /// An alu operation code no operation is defined for.
+    BadAluOp { op: u16, fault: Fault },
+    /// Something failing on the host side, like files, the terminal or sockets.
+    Io(String),
+    /// Bad or conflicting command line arguments, or files named on it that do not parse.
+    Usage(String),
+    /// Assertions of an expectations file that did not hold, summed up.
+    Expectations(String),
+}
+
+impl Error {
+    pub fn memory(message: impl Into<String>) -> Error {
+        Error::Memory {
+            message: message.into(),
+            fault: None,
+        }
+    }
+
+    pub fn unmapped(addr: u16, val: Option<u16>) -> Error {
+        Error::Unmapped {
+            addr,
+            val,
+            fault: None,
+        }
+    }
+
+    /// Attaches the cpu state to a memory error that has none yet.
+    pub fn at(self, fault: impl FnOnce() -> Fault) -> Error {
+        match self {
+            Error::Memory {
+                message,
+                fault: None,
+            } => Error::Memory {
+                message,
+                fault: Some(fault()),
+            },
+            Error::Unmapped {
+                addr,
+                val,
+                fault: None,
+            } => Error::Unmapped {
+                addr,
+                val,
+                fault: Some(fault()),
+            },
+            e => e,
+        }
+    }
+
+    /// Says what the cpu was doing when a memory error without a fault happened.
+    pub fn context(self, what: &str) -> Error {
+        match self {
+            Error::Memory {
+                message,
+                fault: None,
+            } => Error::memory(format!("{what}: {message}")),
+            e @ Error::Unmapped { fault: None, .. } => Error::memory(format!("{what}: {e}")),
+            e => e,
+        }
+    }
+
+    pub fn fault(&self) -> Option<&Fault> {
+        match self {
+            Error::Memory { fault, .. } | Error::Unmapped { fault, .. } => fault.as_ref(),
+            Error::IllegalInstruction { fault } | Error::BadAluOp { fault, .. } => Some(fault),
+            Error::Io(_) | Error::Usage(_) | Error::Expectations(_) => None,
+        }
+    }
+
+    /// Formats the error with the addresses in it named from `symbols`, `Display` leaves them
+    /// as numbers.
+    pub fn display<'a>(&'a self, symbols: &'a Symbols) -> Symbolic<'a, Error> {
+        Symbolic {
+            value: self,
+            symbols,
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Usage(_) => 2,
+            Error::Memory { .. } | Error::Unmapped { .. } => 3,
+            Error::IllegalInstruction { .. } => 4,
+            Error::BadAluOp { .. } => 5,
+            Error::Expectations(_) => 6,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.display(&Symbols::EMPTY).fmt(f)
+    }
+}
+
+impl Display for Symbolic<'_, Error> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let symbols = self.symbols;
+        match self.value {
+            Error::Memory {
+                message,
+                fault: None,
+            } => write!(f, "{message}"),
+            Error::Memory {
+                message,
+                fault: Some(fault),
+            } => write!(f, "{message} at {}", fault.display(symbols)),
+            Error::Unmapped { addr, val, fault } => {
+                write!(f, "Memory location {}", Addr(*addr, symbols))?;
+                if let Some(val) = val {
+                    write!(f, "={val:#06x}")?;
+                }
+                if *addr < 0x8000 {
+                    write!(f, " is past the end of vram")?;
+                } else {
+                    write!(f, " not implemented")?;
+                }
+                match fault {
+                    Some(fault) => write!(f, " at {}", fault.display(symbols)),
+                    None => Ok(()),
+                }
+            }
+            Error::IllegalInstruction { fault } => {
+                write!(f, "Illegal instruction at {}", fault.display(symbols))
+            }
+            Error::BadAluOp { op, fault } => {
+                write!(
+                    f,
+                    "Illegal alu operation {op:#x} at {}",
+                    fault.display(symbols)
+                )
+            }
+            Error::Io(message) | Error::Usage(message) | Error::Expectations(message) => {
+                write!(f, "{message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,482 @@
+use std::fs;
+
+use emu::hardware::def::VGA_HEIGHT;
+use emu::hardware::register::REGISTER_NAMES;
+use emu::screen::rows;
+use emu::symbols::{Addr, Symbols};
+use emu::{Cpu, Error, Step};
+
+use crate::args::Options;
+
+/*
+ *  Expectations files are text, one assertion per line, # starts a comment:
+ *
+ *  limit 2000000             -- run at most this many instructions, --stop-after overrides it
+ *  stop halt                 -- how the run has to end: halt, limit, memory, illegal-instruction
+ *                               or illegal-alu-op. Without a stop line it has to halt
+ *  reg v0 0x0037             -- a register, by name or as r0..r15
+ *  mem 0x8000 1 2 0x3        -- consecutive words from an address on, which can be a symbol
+ *  mem 0x8000..0x800f 0      -- every word of an inclusive range
+ *  row 0 "1:  Passed"        -- the text of a screen row, trailing spaces are ignored
+ *
+ *  Values are decimal, negative decimal or 0x prefixed hex. All assertions are checked against
+ *  the machine as the run left it, also when it ended with an error.
+ */
+
+/// The limit for files without one, so a program that never halts fails instead of hanging.
+const DEFAULT_LIMIT: u64 = 100_000_000;
+/// Differing words listed per mem assertion, the rest are only counted.
+const MAX_DIFFS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    Halt,
+    Limit,
+    Memory,
+    IllegalInstruction,
+    IllegalAluOp,
+}
+
+impl Stop {
+    const ALL: [Stop; 5] = [
+        Stop::Halt,
+        Stop::Limit,
+        Stop::Memory,
+        Stop::IllegalInstruction,
+        Stop::IllegalAluOp,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stop::Halt => "halt",
+            Stop::Limit => "limit",
+            Stop::Memory => "memory",
+            Stop::IllegalInstruction => "illegal-instruction",
+            Stop::IllegalAluOp => "illegal-alu-op",
+        }
+    }
+}
+
+enum Assertion {
+    Stop(Stop),
+    Register { reg: u16, val: u16 },
+    Memory { addr: u16, vals: Vec<u16> },
+    Range { start: u16, end: u16, val: u16 },
+    Row { row: usize, text: String },
+}
+
+/// An assertion and the line it came from, for the report.
+struct Line {
+    number: usize,
+    text: String,
+    assertion: Assertion,
+}
+
+/// The `--expect` file, checked once the interpreter stops.
+pub struct Expectations {
+    path: String,
+    limit: Option<u64>,
+    lines: Vec<Line>,
+}
+
+fn number(s: &str) -> Result<u16, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16).ok(),
+        None => s.parse::<i32>().ok(),
+    };
+    parsed
+        .filter(|n| (-0x8000..=0xFFFF).contains(n))
+        .map(|n| n as u16)
+        .ok_or_else(|| format!("{s} is not a 16 bit number"))
+}
+
+fn address(s: &str, symbols: &Symbols) -> Result<u16, String> {
+    symbols
+        .find(s)
+        .map_or_else(|| number(s), Ok)
+        .map_err(|_| format!("{s} is not an address or symbol"))
+}
+
+fn register(s: &str) -> Result<u16, String> {
+    REGISTER_NAMES
+        .iter()
+        .position(|name| *name == s)
+        .map(|r| r as u16)
+        .or_else(|| {
+            s.strip_prefix('r')
+                .and_then(|r| r.parse::<u16>().ok())
+                .filter(|r| *r < 16)
+        })
+        .ok_or_else(|| format!("{s} is not a register"))
+}
+
+/// Drops a comment, unless the # is inside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_row(args: &str) -> Result<Assertion, String> {
+    let (row, text) = args
+        .split_once(char::is_whitespace)
+        .ok_or("expected row ROW \"TEXT\"")?;
+    let row = number(row)? as usize;
+    if row >= VGA_HEIGHT {
+        return Err(format!(
+            "row {row} is off the screen, which has {VGA_HEIGHT} rows"
+        ));
+    }
+    let text = text
+        .trim()
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or("the row text has to be in double quotes")?;
+    Ok(Assertion::Row {
+        row,
+        text: text.trim_end().to_string(),
+    })
+}
+
+fn parse_memory(words: &[&str], symbols: &Symbols) -> Result<Assertion, String> {
+    let (addr, vals) = words.split_first().ok_or("expected mem ADDR VALUE...")?;
+    let vals = vals
+        .iter()
+        .map(|val| number(val))
+        .collect::<Result<Vec<u16>, String>>()?;
+    if vals.is_empty() {
+        return Err("mem expects at least one value".to_string());
+    }
+    match addr.split_once("..") {
+        Some((start, end)) => {
+            let (start, end) = (address(start, symbols)?, address(end, symbols)?);
+            if end < start || vals.len() != 1 {
+                return Err("a mem range goes from low to high and takes one value".to_string());
+            }
+            Ok(Assertion::Range {
+                start,
+                end,
+                val: vals[0],
+            })
+        }
+        None => {
+            let addr = address(addr, symbols)?;
+            if addr as usize + vals.len() > 0x10000 {
+                return Err("the values run past the end of memory".to_string());
+            }
+            Ok(Assertion::Memory { addr, vals })
+        }
+    }
+}
+
+/// Parses one line, a limit line sets `limit` and returns none. Addresses can be names from
+/// `symbols`.
+fn parse_line(
+    line: &str,
+    symbols: &Symbols,
+    limit: &mut Option<u64>,
+) -> Result<Option<Assertion>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let arg = |n: usize| {
+        words
+            .get(n)
+            .copied()
+            .ok_or_else(|| format!("{} expects more arguments", words[0]))
+    };
+    let assertion = match words[0] {
+        "limit" => {
+            let n = arg(1)?;
+            *limit = Some(
+                n.parse::<u64>()
+                    .map_err(|_| format!("{n} is not a count"))?,
+            );
+            return Ok(None);
+        }
+        "stop" => {
+            let name = arg(1)?;
+            let stop = Stop::ALL.into_iter().find(|s| s.name() == name).ok_or_else(|| {
+                format!("unknown stop {name}, expected halt, limit, memory, illegal-instruction or illegal-alu-op")
+            })?;
+            Assertion::Stop(stop)
+        }
+        "reg" => Assertion::Register {
+            reg: register(arg(1)?)?,
+            val: number(arg(2)?)?,
+        },
+        "mem" => parse_memory(&words[1..], symbols)?,
+        "row" => parse_row(line["row".len()..].trim_start())?,
+        word => {
+            return Err(format!(
+                "unknown assertion {word}, expected limit, stop, reg, mem or row"
+            ))
+        }
+    };
+    Ok(Some(assertion))
+}
+
+impl Expectations {
+    /// Returns the expectations if `--expect` was given.
+    pub fn from_options(opts: &Options, symbols: &Symbols) -> Result<Option<Expectations>, Error> {
+        if opts.expect.is_empty() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(&opts.expect)
+            .map_err(|e| Error::Io(format!("Could not read expectations {}: {e}", opts.expect)))?;
+        Expectations::parse(&opts.expect, &text, symbols)
+            .map(Some)
+            .map_err(|e| Error::Usage(format!("Could not load expectations {}: {e}", opts.expect)))
+    }
+
+    fn parse(path: &str, text: &str, symbols: &Symbols) -> Result<Expectations, String> {
+        let mut limit = None;
+        let mut lines = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let assertion = parse_line(line, symbols, &mut limit)
+                .map_err(|e| format!("line {}: {e}", i + 1))?;
+            if let Some(assertion) = assertion {
+                lines.push(Line {
+                    number: i + 1,
+                    text: line.to_string(),
+                    assertion,
+                });
+            }
+        }
+        Ok(Expectations {
+            path: path.to_string(),
+            limit,
+            lines,
+        })
+    }
+
+    /// The instruction limit of the run: `--stop-after`, the file's limit, or the default.
+    pub fn limit(&self, opts: &Options) -> u64 {
+        match opts.stop_after {
+            0 => self.limit.unwrap_or(DEFAULT_LIMIT),
+            n => n,
+        }
+    }
+
+    /// Checks the machine after the interpreter returned `result`, printing every assertion that
+    /// does not hold. Errors the cpu stopped on are a stop reason, others are passed on.
+    pub fn check(&self, cpu: &Cpu, result: Result<Step, Error>) -> Result<(), Error> {
+        let (stop, error) = match result {
+            Ok(Step::Halt) => (Stop::Halt, None),
+            Ok(_) => (Stop::Limit, None),
+            Err(e) => {
+                let stop = match e {
+                    Error::Memory { .. } | Error::Unmapped { .. } => Stop::Memory,
+                    Error::IllegalInstruction { .. } => Stop::IllegalInstruction,
+                    Error::BadAluOp { .. } => Stop::IllegalAluOp,
+                    _ => return Err(e),
+                };
+                (stop, Some(e))
+            }
+        };
+
+        let mut failures = Vec::new();
+        let mut total = self.lines.len();
+        if !self
+            .lines
+            .iter()
+            .any(|line| matches!(line.assertion, Assertion::Stop(_)))
+        {
+            total += 1;
+            if let Some(failure) = check_stop(cpu, Stop::Halt, stop, &error) {
+                failures.push(format!("{}: the run did not halt\n{failure}", self.path));
+            }
+        }
+        for line in &self.lines {
+            let failure = match &line.assertion {
+                Assertion::Stop(expected) => check_stop(cpu, *expected, stop, &error),
+                assertion => check_state(cpu, assertion),
+            };
+            if let Some(failure) = failure {
+                failures.push(format!(
+                    "{}:{}: {}\n{failure}",
+                    self.path, line.number, line.text
+                ));
+            }
+        }
+
+        if failures.is_empty() {
+            println!(
+                "{}: {total} of {total} expectations met after {} instructions",
+                self.path,
+                cpu.count()
+            );
+            return Ok(());
+        }
+        for failure in &failures {
+            println!("{failure}");
+        }
+        Err(Error::Expectations(format!(
+            "{}: {} of {total} expectations failed after {} instructions",
+            self.path,
+            failures.len(),
+            cpu.count()
+        )))
+    }
+}
+
+fn check_stop(cpu: &Cpu, expected: Stop, stop: Stop, error: &Option<Error>) -> Option<String> {
+    if expected == stop {
+        return None;
+    }
+    let mut failure = format!("  expected {}, got {}", expected.name(), stop.name());
+    if let Some(e) = error {
+        failure.push_str(&format!(": {}", e.display(cpu.mem.symbols())));
+    }
+    Some(failure)
+}
+
+fn check_state(cpu: &Cpu, assertion: &Assertion) -> Option<String> {
+    let symbols = cpu.mem.symbols();
+    let word = |addr: u16| cpu.peek(addr).map_err(|e| e.display(symbols).to_string());
+    let diff = |addr: u16, expected: u16| match word(addr) {
+        Ok(val) if val == expected => None,
+        Ok(val) => Some(format!(
+            "  {}: expected {expected:#06x}, got {val:#06x}",
+            Addr(addr, symbols)
+        )),
+        Err(e) => Some(format!(
+            "  {}: expected {expected:#06x}, got {e}",
+            Addr(addr, symbols)
+        )),
+    };
+
+    let failures: Vec<String> = match assertion {
+        Assertion::Stop(_) => Vec::new(),
+        Assertion::Register { reg, val } => {
+            let got = cpu.registers[*reg];
+            (got != *val)
+                .then(|| format!("  expected {val:#06x}, got {got:#06x}"))
+                .into_iter()
+                .collect()
+        }
+        Assertion::Memory { addr, vals } => vals
+            .iter()
+            .enumerate()
+            .filter_map(|(i, val)| diff(addr + i as u16, *val))
+            .collect(),
+        Assertion::Range { start, end, val } => (*start..=*end)
+            .filter_map(|addr| diff(addr, *val))
+            .collect(),
+        Assertion::Row { row, text } => {
+            let got: String = rows(cpu.mem.vram())[*row]
+                .iter()
+                .map(|cell| cell.printable())
+                .collect();
+            let got = got.trim_end();
+            (got != text)
+                .then(|| format!("  expected |{text}|\n  got      |{got}|"))
+                .into_iter()
+                .collect()
+        }
+    };
+    if failures.len() > MAX_DIFFS {
+        let more = failures.len() - MAX_DIFFS;
+        return Some(format!(
+            "{}\n  and {more} more",
+            failures[..MAX_DIFFS].join("\n")
+        ));
+    }
+    (!failures.is_empty()).then(|| failures.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Expectations, String> {
+        let symbols = Symbols::parse("8010 data counter 1\n").unwrap();
+        Expectations::parse("test.expect", text, &symbols)
+    }
+
+    #[test]
+    fn assertions() {
+        let expectations = parse(
+            "\
+# a comment
+limit 2000
+
+stop illegal-alu-op
+reg v0 0x0037   # trailing comment
+reg r15 -1
+mem counter 1 2 0x3
+mem 0x8000..0x800f 0
+row 0 \"1: # Passed  \"
+",
+        )
+        .unwrap();
+        assert_eq!(expectations.limit, Some(2000));
+        let numbers: Vec<usize> = expectations.lines.iter().map(|l| l.number).collect();
+        assert_eq!(numbers, [4, 5, 6, 7, 8, 9]);
+        assert_eq!(expectations.lines[1].text, "reg v0 0x0037");
+
+        let assertions: Vec<&Assertion> = expectations.lines.iter().map(|l| &l.assertion).collect();
+        assert!(matches!(assertions[0], Assertion::Stop(Stop::IllegalAluOp)));
+        assert!(matches!(
+            assertions[1],
+            Assertion::Register { reg: 5, val: 0x37 }
+        ));
+        assert!(matches!(
+            assertions[2],
+            Assertion::Register {
+                reg: 15,
+                val: 0xFFFF
+            }
+        ));
+        assert!(
+            matches!(assertions[3], Assertion::Memory { addr: 0x8010, vals } if vals == &[1, 2, 3])
+        );
+        assert!(matches!(
+            assertions[4],
+            Assertion::Range {
+                start: 0x8000,
+                end: 0x800f,
+                val: 0
+            }
+        ));
+        assert!(matches!(assertions[5], Assertion::Row { row: 0, text } if text == "1: # Passed"));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| parse(text).err().unwrap();
+        assert_eq!(error("\nreg x9 1"), "line 2: x9 is not a register");
+        assert_eq!(
+            error("reg v0 0x10000"),
+            "line 1: 0x10000 is not a 16 bit number"
+        );
+        assert_eq!(error("reg v0"), "line 1: reg expects more arguments");
+        assert_eq!(
+            error("mem nowhere 1"),
+            "line 1: nowhere is not an address or symbol"
+        );
+        assert_eq!(
+            error("mem 0xffff 1 2"),
+            "line 1: the values run past the end of memory"
+        );
+        assert_eq!(
+            error("mem 0x8010..0x8000 0"),
+            "line 1: a mem range goes from low to high and takes one value"
+        );
+        assert_eq!(
+            error("row 1 Passed"),
+            "line 1: the row text has to be in double quotes"
+        );
+        assert_eq!(error("limit lots"), "line 1: lots is not a count");
+        assert!(error("stop crash").starts_with("line 1: unknown stop crash"));
+        assert!(error("check v0").starts_with("line 1: unknown assertion check"));
+    }
+}
@@ -0,0 +1,46 @@
+use emu::screen::raster::save_png;
+use emu::{Cpu, Error};
+
+use crate::args::Options;
+
+pub const DEFAULT_FRAME_INTERVAL: u64 = 100_000;
+
+/// Renders the screen to a numbered png every `interval` instructions, for `--frames`. Frames
+/// are taken by instruction count, so a replayed run renders the same sequence every time.
+pub struct Frames {
+    prefix: String,
+    interval: u64,
+    next: u64,
+    frame: u32,
+}
+
+impl Frames {
+    pub fn from_options(opts: &Options) -> Result<Option<Frames>, Error> {
+        if opts.frames.is_empty() {
+            return Ok(None);
+        }
+        if opts.frame_interval == 0 {
+            return Err(Error::Usage(
+                "--frame-interval has to be at least one instruction".to_string(),
+            ));
+        }
+        Ok(Some(Frames {
+            prefix: opts.frames.clone(),
+            interval: opts.frame_interval,
+            next: 0,
+            frame: 0,
+        }))
+    }
+
+    /// Renders a frame if one is due. Call after every step.
+    pub fn after_step(&mut self, cpu: &Cpu) -> Result<(), Error> {
+        if cpu.count() < self.next {
+            return Ok(());
+        }
+        save_png(cpu.mem.vram(), &format!("{}{:05}.png", self.prefix, self.frame))
+            .map_err(Error::Io)?;
+        self.frame += 1;
+        self.next = (cpu.count() / self.interval + 1) * self.interval;
+        Ok(())
+    }
+}
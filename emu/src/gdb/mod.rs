@@ -0,0 +1,433 @@
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+
+use emu::hardware::register::REGISTER_NAMES;
+use emu::loader::Program;
+use emu::symbols::Symbols;
+use emu::{Cpu, Error, Step};
+
+use crate::args::Options;
+use crate::emulator;
+use crate::socket;
+
+// How many instructions to run between checks for an interrupt from gdb
+const POLL_INTERVAL: u64 = 4096;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Listens on a tcp port on localhost if `addr` is a number, otherwise on a unix socket. A
+    /// socket left at `addr` by an earlier run is replaced, other files are not.
+    fn accept(addr: &str) -> Result<Stream, Error> {
+        let io_error =
+            |e: io::Error| Error::Io(format!("Could not accept gdb connection on {addr}: {e}"));
+        if let Ok(port) = addr.parse::<u16>() {
+            let listener = TcpListener::bind(("127.0.0.1", port)).map_err(io_error)?;
+            println!("waiting for gdb on 127.0.0.1:{port}");
+            let (stream, _) = listener.accept().map_err(io_error)?;
+            stream.set_nodelay(true).map_err(io_error)?;
+            Ok(Stream::Tcp(stream))
+        } else {
+            socket::remove_stale(addr)?;
+            let listener = UnixListener::bind(addr).map_err(io_error)?;
+            println!("waiting for gdb on {addr}");
+            let (stream, _) = listener.accept().map_err(io_error)?;
+            Ok(Stream::Unix(stream))
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nonblocking(nonblocking),
+            Stream::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+fn target_xml() -> String {
+    let regs: String = REGISTER_NAMES
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let kind = match *name {
+                "pc" => "code_ptr",
+                "sp" => "data_ptr",
+                _ => "uint16",
+            };
+            format!("<reg name=\"r{i}\" bitsize=\"16\" type=\"{kind}\" regnum=\"{i}\"/>")
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target><feature name=\"org.toast.core\">{regs}</feature></target>"
+    )
+}
+
+fn hex_u16(val: u16) -> String {
+    // registers and words go over the wire little endian
+    format!("{:02x}{:02x}", val & 0xFF, val >> 8)
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 16).map_err(|_| format!("{s} is not a hex number"))
+}
+
+fn parse_hex_u16(s: &str) -> Result<u16, String> {
+    if s.len() != 4 {
+        return Err(format!("{s} is not a 16 bit register value"));
+    }
+    let lo = parse_hex(&s[0..2])? as u16;
+    let hi = parse_hex(&s[2..4])? as u16;
+    Ok(hi << 8 | lo)
+}
+
+/// Remote serial protocol stub. Addresses are word addresses, the same values held in pc and
+/// sp, and each word takes up two bytes on the wire.
+struct Stub<'a> {
+    stream: Stream,
+    no_ack: bool,
+    cpu: &'a mut Cpu,
+    breakpoints: BTreeSet<u16>,
+    halted: bool,
+}
+
+impl<'a> Stub<'a> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.stream.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Reads the next packet, asking gdb to send it again until its checksum matches.
+    fn read_packet(&mut self) -> io::Result<Packet> {
+        loop {
+            loop {
+                match self.read_byte()? {
+                    0x03 => return Ok(Packet::Interrupt),
+                    b'$' => break,
+                    _ => continue,
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            if !self.no_ack {
+                let expected = format!("{:02x}", data.iter().fold(0u8, |a, b| a.wrapping_add(*b)));
+                if expected.as_bytes() != checksum.to_ascii_lowercase() {
+                    self.stream.write_all(b"-")?;
+                    continue;
+                }
+                self.stream.write_all(b"+")?;
+            }
+
+            return Ok(Packet::Command(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+        loop {
+            write!(self.stream, "${data}#{checksum:02x}")?;
+            self.stream.flush()?;
+
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                b'-' => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Checks for a pending ^C from gdb without blocking.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0u8; 1];
+        let res = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+
+        match res {
+            Ok(1) => Ok(buf[0] == 0x03),
+            Ok(_) => Err(io::Error::from(ErrorKind::UnexpectedEof)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs until a breakpoint, halt, error or interrupt and returns the stop reply.
+    fn resume(&mut self, single: bool) -> io::Result<String> {
+        if self.halted {
+            return Ok("W00".to_string());
+        }
+
+        let mut steps: u64 = 0;
+        loop {
+            match self.cpu.step() {
+                Ok(Step::Halt) => {
+                    self.halted = true;
+                    return Ok("W00".to_string());
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    // console output shows up in gdb ahead of the stop
+                    let message = format!("{}\n", e.display(self.cpu.mem.symbols()));
+                    self.send(&format!("O{}", hex_bytes(message.as_bytes())))?;
+                    let signal = match e {
+                        Error::IllegalInstruction { .. } | Error::BadAluOp { .. } => SIGILL,
+                        _ => SIGSEGV,
+                    };
+                    return Ok(format!("S{signal:02x}"));
+                }
+            }
+
+            steps += 1;
+            if single || self.breakpoints.contains(&self.cpu.registers.pc) {
+                return Ok(format!("S{SIGTRAP:02x}"));
+            }
+            if steps.is_multiple_of(POLL_INTERVAL) && self.interrupted()? {
+                return Ok(format!("S{SIGINT:02x}"));
+            }
+        }
+    }
+
+    fn read_memory(&self, addr: u16, len: u32) -> String {
+        let mut out = String::new();
+        for i in 0..len {
+            let word = match self.cpu.peek(addr.wrapping_add((i / 2) as u16)) {
+                Ok(word) => word,
+                Err(_) if i == 0 => return "E01".to_string(),
+                Err(_) => break,
+            };
+            let byte = if i % 2 == 0 { word & 0xFF } else { word >> 8 };
+            out.push_str(&format!("{byte:02x}"));
+        }
+        out
+    }
+
+    fn write_memory(&mut self, addr: u16, data: &str) -> Result<(), String> {
+        if !data.len().is_multiple_of(4) {
+            return Err("memory writes must be whole words".to_string());
+        }
+        for i in 0..data.len() / 4 {
+            let word = parse_hex_u16(&data[i * 4..i * 4 + 4])?;
+            self.cpu
+                .mem
+                .write(addr.wrapping_add(i as u16), word)
+                .map_err(|e| e.display(self.cpu.mem.symbols()).to_string())?;
+        }
+        Ok(())
+    }
+
+    fn features(&self, args: &str) -> Result<String, String> {
+        // target.xml:offset,length
+        let (annex, range) = args.split_once(':').ok_or("bad qXfer request")?;
+        if annex != "target.xml" {
+            return Ok("E00".to_string());
+        }
+        let (off, len) = range.split_once(',').ok_or("bad qXfer range")?;
+        let (off, len) = (parse_hex(off)? as usize, parse_hex(len)? as usize);
+
+        let xml = target_xml();
+        if off >= xml.len() {
+            return Ok("l".to_string());
+        }
+        let end = xml.len().min(off + len);
+        let more = if end < xml.len() { "m" } else { "l" };
+        Ok(format!("{more}{}", &xml[off..end]))
+    }
+
+    /// Handles one command, returning the reply or None when the session is over.
+    fn handle(&mut self, cmd: &str) -> Result<Option<String>, String> {
+        let reply = match cmd.as_bytes().first() {
+            None => String::new(),
+            Some(b'?') => {
+                if self.halted {
+                    "W00".to_string()
+                } else {
+                    format!("S{SIGTRAP:02x}")
+                }
+            }
+            Some(b'g') => (0..16u16).map(|r| hex_u16(self.cpu.registers[r])).collect(),
+            Some(b'G') => {
+                let data = &cmd[1..];
+                for r in 0..16u16 {
+                    let at = r as usize * 4;
+                    if let Some(val) = data.get(at..at + 4) {
+                        self.cpu.registers[r] = parse_hex_u16(val)?;
+                    }
+                }
+                "OK".to_string()
+            }
+            Some(b'p') => {
+                let r = parse_hex(&cmd[1..])?;
+                if r < 16 {
+                    hex_u16(self.cpu.registers[r as u16])
+                } else {
+                    "E00".to_string()
+                }
+            }
+            Some(b'P') => {
+                let (r, val) = cmd[1..].split_once('=').ok_or("bad register write")?;
+                let r = parse_hex(r)?;
+                if r < 16 {
+                    self.cpu.registers[r as u16] = parse_hex_u16(val)?;
+                    "OK".to_string()
+                } else {
+                    "E00".to_string()
+                }
+            }
+            Some(b'm') => {
+                let (addr, len) = cmd[1..].split_once(',').ok_or("bad memory read")?;
+                self.read_memory(parse_hex(addr)? as u16, parse_hex(len)?)
+            }
+            Some(b'M') => {
+                let (range, data) = cmd[1..].split_once(':').ok_or("bad memory write")?;
+                let (addr, _) = range.split_once(',').ok_or("bad memory write")?;
+                match self.write_memory(parse_hex(addr)? as u16, data) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => "E01".to_string(),
+                }
+            }
+            Some(b'Z') | Some(b'z') => {
+                let mut parts = cmd[1..].split(',');
+                let kind = parts.next().unwrap_or_default();
+                let addr = parse_hex(parts.next().ok_or("bad breakpoint")?)? as u16;
+                if kind != "0" && kind != "1" {
+                    String::new()
+                } else {
+                    if cmd.starts_with('Z') {
+                        self.breakpoints.insert(addr);
+                    } else {
+                        self.breakpoints.remove(&addr);
+                    }
+                    "OK".to_string()
+                }
+            }
+            Some(b's') | Some(b'c') => {
+                if cmd.len() > 1 {
+                    self.cpu.registers.pc = parse_hex(&cmd[1..])? as u16;
+                }
+                self.resume(cmd.starts_with('s')).map_err(|e| format!("{e}"))?
+            }
+            Some(b'k') => return Ok(None),
+            Some(b'D') => {
+                self.send("OK").map_err(|e| format!("{e}"))?;
+                return Ok(None);
+            }
+            Some(b'H') => "OK".to_string(),
+            _ if cmd.starts_with("qSupported") => {
+                "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string()
+            }
+            _ if cmd == "QStartNoAckMode" => "OK".to_string(),
+            _ if cmd.starts_with("qXfer:features:read:") => {
+                self.features(&cmd["qXfer:features:read:".len()..])?
+            }
+            _ if cmd == "qAttached" => "1".to_string(),
+            _ if cmd == "qfThreadInfo" => "m1".to_string(),
+            _ if cmd == "qsThreadInfo" => "l".to_string(),
+            _ if cmd == "qC" => "QC1".to_string(),
+            _ if cmd == "vCont?" => "vCont;c;s".to_string(),
+            _ if cmd.starts_with("vCont;") => {
+                let single = cmd["vCont;".len()..].starts_with('s');
+                self.resume(single).map_err(|e| format!("{e}"))?
+            }
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn serve(&mut self) -> Result<(), String> {
+        loop {
+            let cmd = match self.read_packet() {
+                Ok(Packet::Command(cmd)) => cmd,
+                // interrupts only mean something while running, which `resume` polls for
+                Ok(Packet::Interrupt) => continue,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(format!("Lost connection to gdb: {e}")),
+            };
+
+            match self.handle(&cmd) {
+                Ok(Some(reply)) => self.send(&reply),
+                Ok(None) => return Ok(()),
+                Err(e) => self.send(&format!("E.{e}")),
+            }
+            .map_err(|e| format!("Lost connection to gdb: {e}"))?;
+
+            // the reply to QStartNoAckMode is itself still acknowledged
+            if cmd == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+    }
+}
+
+/// Waits for gdb to connect on `opts.gdb`, then serves it until it detaches or kills the target.
+pub fn gdb(program: Program, opts: &Options, symbols: &Arc<Symbols>) -> Result<(), Error> {
+    let stream = Stream::accept(&opts.gdb)?;
+
+    emulator::run(program, opts, symbols, |cpu| {
+        Stub {
+            stream,
+            no_ack: false,
+            cpu,
+            breakpoints: BTreeSet::new(),
+            halted: false,
+        }
+        .serve()
+        .map_err(Error::Io)
+    })
+}
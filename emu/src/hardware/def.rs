@@ -1,5 +1,11 @@
 pub const ROM_SIZE: usize = 0x8000;
 pub const RAM_SIZE: usize = 0x4000;
+pub const IO_START: u16 = 0xC000;
+pub const EXC_BASE: u16 = 0xFFD0;
+pub const INTC_BASE: u16 = 0xFFE0;
+pub const TIMER_BASE: u16 = 0xFFF0;
+pub const UART_BASE: u16 = 0xFFF8;
+pub const KEYBOARD_BASE: u16 = 0xFFFE;
 pub const VGA_WIDTH: usize = 100;
 pub const VGA_HEIGHT: usize = 60;
 
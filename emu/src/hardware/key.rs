@@ -4,7 +4,8 @@ use std::{
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex,
-    }, time::Duration,
+    },
+    time::Duration,
 };
 
 use crossterm::{
@@ -15,30 +16,24 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode},
 };
 
+use crate::devices::KeyFifo;
+
+use super::ps2::{Ps2Decoder, Ps2Keyboard};
+
 pub struct Key {
-    irq: Arc<Mutex<bool>>,
-    key: Arc<Mutex<u16>>,
+    fifo: Arc<Mutex<KeyFifo>>,
+    keyboard: Ps2Keyboard,
+    decoder: Ps2Decoder,
 }
 
-const CODES: [u16; 26] = [
-    0x001C, 0x0032, 0x0021, 0x0023, 0x0024, 0x002B, 0x0034, 0x0033, 0x0043, 0x003B, 0x0042, 0x004B,
-    0x003A, 0x0031, 0x0044, 0x004D, 0x0015, 0x002D, 0x001B, 0x002C, 0x003C, 0x002A, 0x001D, 0x0022,
-    0x0035, 0x001A,
-];
-
 impl Key {
-    fn convert_to_scan_code(&self, c: u8) -> u16 {
-        CODES[(c - b'a') as usize]
-    }
-
-    pub fn new(irq: Arc<Mutex<bool>>, key: Arc<Mutex<u16>>) -> Key {
+    pub fn new(fifo: Arc<Mutex<KeyFifo>>) -> Key {
         enable_raw_mode().unwrap();
-        Key { irq, key }
-    }
-
-    fn irq(&mut self, code: u16) {
-        *self.irq.lock().unwrap() = true;
-        *self.key.lock().unwrap() = code;
+        Key {
+            fifo,
+            keyboard: Ps2Keyboard::new(),
+            decoder: Ps2Decoder::new(),
+        }
     }
 
     pub fn handle(&mut self, term: &AtomicBool) {
@@ -49,20 +44,6 @@ impl Key {
             
             let event = read().unwrap();
             match event {
-                Event::Key(KeyEvent {
-                    code: KeyCode::Char(c),
-                    modifiers: KeyModifiers::NONE,
-                }) => {
-                    if c.is_ascii_lowercase() {
-                        self.irq(self.convert_to_scan_code(c as u8));
-                    }
-                }
-                Event::Key(KeyEvent {
-                    code: KeyCode::Enter,
-                    modifiers: KeyModifiers::NONE,
-                }) => {
-                    self.irq(0x005A);
-                }
                 Event::Key(KeyEvent {
                     code: KeyCode::Char('c'),
                     modifiers: KeyModifiers::CONTROL,
@@ -71,6 +52,13 @@ impl Key {
                     disable_raw_mode().unwrap();
                     exit(0);
                 }
+                Event::Key(event) => {
+                    for byte in self.keyboard.press(event) {
+                        if let Some(code) = self.decoder.receive(byte) {
+                            self.fifo.lock().unwrap().push(code);
+                        }
+                    }
+                }
                 _ => (),
             };
         }
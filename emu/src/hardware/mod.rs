@@ -1,4 +1,5 @@
 pub mod def;
 pub mod key;
+pub mod ps2;
 pub mod register;
 pub mod vga;
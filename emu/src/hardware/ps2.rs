@@ -0,0 +1,221 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::devices::keyboard::RELEASED;
+
+/*
+ *  PS/2 scan code set 2, as sent by a keyboard on the DE1-SoC PS/2 port:
+ *
+ *  1C                            -- make code, key pressed
+ *  F0 1C                         -- break code, key released
+ *  E0 75                         -- make code of an extended key
+ *  E0 F0 75                      -- break code of an extended key
+ */
+
+const EXTENDED: u8 = 0xE0;
+const BREAK: u8 = 0xF0;
+
+const LEFT_SHIFT: u8 = 0x12;
+const LEFT_CTRL: u8 = 0x14;
+const LEFT_ALT: u8 = 0x11;
+
+/// Make codes of the letters a to z.
+const LETTERS: [u8; 26] = [
+    0x1C, 0x32, 0x21, 0x23, 0x24, 0x2B, 0x34, 0x33, 0x43, 0x3B, 0x42, 0x4B, 0x3A, 0x31, 0x44,
+    0x4D, 0x15, 0x2D, 0x1B, 0x2C, 0x3C, 0x2A, 0x1D, 0x22, 0x35, 0x1A,
+];
+
+/// Make codes of the digits 0 to 9.
+const DIGITS: [u8; 10] = [0x45, 0x16, 0x1E, 0x26, 0x25, 0x2E, 0x36, 0x3D, 0x3E, 0x46];
+
+/// Make codes of F1 to F12.
+const FUNCTION: [u8; 12] = [
+    0x05, 0x06, 0x04, 0x0C, 0x03, 0x0B, 0x83, 0x0A, 0x01, 0x09, 0x78, 0x07,
+];
+
+/// Punctuation on a US layout: unshifted, shifted, make code.
+const PUNCTUATION: [(char, char, u8); 11] = [
+    ('`', '~', 0x0E),
+    ('-', '_', 0x4E),
+    ('=', '+', 0x55),
+    ('[', '{', 0x54),
+    (']', '}', 0x5B),
+    ('\\', '|', 0x5D),
+    (';', ':', 0x4C),
+    ('\'', '"', 0x52),
+    (',', '<', 0x41),
+    ('.', '>', 0x49),
+    ('/', '?', 0x4A),
+];
+
+/// Shifted digits on a US layout, starting at 0.
+const SHIFTED_DIGITS: [char; 10] = [')', '!', '@', '#', '$', '%', '^', '&', '*', '('];
+
+/// A key's make code, and whether it is E0-prefixed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Code {
+    extended: bool,
+    code: u8,
+}
+
+const fn plain(code: u8) -> Code {
+    Code { extended: false, code }
+}
+
+const fn extended(code: u8) -> Code {
+    Code { extended: true, code }
+}
+
+/// The key typing `c` on a US layout, and whether shift has to be held for it.
+fn char_code(c: char) -> Option<(Code, bool)> {
+    match c {
+        'a'..='z' => Some((plain(LETTERS[c as usize - 'a' as usize]), false)),
+        'A'..='Z' => Some((plain(LETTERS[c as usize - 'A' as usize]), true)),
+        '0'..='9' => Some((plain(DIGITS[c as usize - '0' as usize]), false)),
+        ' ' => Some((plain(0x29), false)),
+        _ => {
+            if let Some(digit) = SHIFTED_DIGITS.iter().position(|&d| d == c) {
+                return Some((plain(DIGITS[digit]), true));
+            }
+            PUNCTUATION.iter().find_map(|&(normal, shifted, code)| {
+                (c == normal || c == shifted).then_some((plain(code), c == shifted))
+            })
+        }
+    }
+}
+
+fn key_code(code: KeyCode) -> Option<(Code, bool)> {
+    let key = match code {
+        KeyCode::Char(c) => return char_code(c),
+        KeyCode::BackTab => return Some((plain(0x0D), true)),
+        KeyCode::Enter => plain(0x5A),
+        KeyCode::Backspace => plain(0x66),
+        KeyCode::Tab => plain(0x0D),
+        KeyCode::Esc => plain(0x76),
+        KeyCode::F(n @ 1..=12) => plain(FUNCTION[n as usize - 1]),
+        KeyCode::Left => extended(0x6B),
+        KeyCode::Right => extended(0x74),
+        KeyCode::Up => extended(0x75),
+        KeyCode::Down => extended(0x72),
+        KeyCode::Home => extended(0x6C),
+        KeyCode::End => extended(0x69),
+        KeyCode::PageUp => extended(0x7D),
+        KeyCode::PageDown => extended(0x7A),
+        KeyCode::Insert => extended(0x70),
+        KeyCode::Delete => extended(0x71),
+        _ => return None,
+    };
+    Some((key, false))
+}
+
+/// Turns terminal key events into the byte stream a PS/2 keyboard would send.
+///
+/// Terminals only report presses, so every key is released right after it is pressed. Modifiers
+/// stay held from one event to the next, and are only released once an event comes in without
+/// them, so typing a capitalized word sends a single shift make code.
+#[derive(Debug, Default)]
+pub struct Ps2Keyboard {
+    shift: bool,
+    ctrl: bool,
+    alt: bool,
+}
+
+impl Ps2Keyboard {
+    pub fn new() -> Ps2Keyboard {
+        Ps2Keyboard::default()
+    }
+
+    /// The bytes sent for `event`, empty if the key has no scan code.
+    pub fn press(&mut self, event: KeyEvent) -> Vec<u8> {
+        let Some((key, shifted)) = key_code(event.code) else {
+            return Vec::new();
+        };
+
+        let mut bytes = Vec::new();
+        let modifiers = [
+            (&mut self.shift, shifted || event.modifiers.contains(KeyModifiers::SHIFT), LEFT_SHIFT),
+            (&mut self.ctrl, event.modifiers.contains(KeyModifiers::CONTROL), LEFT_CTRL),
+            (&mut self.alt, event.modifiers.contains(KeyModifiers::ALT), LEFT_ALT),
+        ];
+        for (held, wanted, code) in modifiers {
+            if *held != wanted {
+                if !wanted {
+                    bytes.push(BREAK);
+                }
+                bytes.push(code);
+                *held = wanted;
+            }
+        }
+
+        if key.extended {
+            bytes.extend([EXTENDED, key.code, EXTENDED, BREAK, key.code]);
+        } else {
+            bytes.extend([key.code, BREAK, key.code]);
+        }
+        bytes
+    }
+}
+
+/// Turns PS/2 bytes into the words the cpu reads from the keyboard, the way the FPGA's
+/// `key_driver` does: the scan code in the low byte, with 0xE0 in the high byte for extended
+/// keys. Break codes come out the same way with `keyboard::RELEASED` set, the keyboard only uses
+/// them to keep track of the held modifiers.
+#[derive(Debug, Default)]
+pub struct Ps2Decoder {
+    extended: bool,
+    released: bool,
+}
+
+impl Ps2Decoder {
+    pub fn new() -> Ps2Decoder {
+        Ps2Decoder::default()
+    }
+
+    /// Takes the next byte from the keyboard, returning the word to latch if it ends a make or
+    /// break code.
+    pub fn receive(&mut self, byte: u8) -> Option<u16> {
+        match byte {
+            EXTENDED => self.extended = true,
+            BREAK => self.released = true,
+            code => {
+                let high = if std::mem::take(&mut self.extended) { EXTENDED } else { 0 };
+                let released = if std::mem::take(&mut self.released) { RELEASED } else { 0 };
+                return Some(u16::from_be_bytes([high, code]) | released);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<u16> {
+        let mut decoder = Ps2Decoder::new();
+        bytes.iter().filter_map(|b| decoder.receive(*b)).collect()
+    }
+
+    fn type_keys(events: &[KeyEvent]) -> Vec<u16> {
+        let mut keyboard = Ps2Keyboard::new();
+        let bytes: Vec<u8> = events.iter().flat_map(|e| keyboard.press(*e)).collect();
+        decode(&bytes)
+    }
+
+    #[test]
+    fn make_and_break_codes() {
+        assert_eq!(
+            decode(&[0x1C, 0xF0, 0x1C, 0xE0, 0x75, 0xE0, 0xF0, 0x75]),
+            [0x001C, 0x011C, 0xE075, 0xE175]
+        );
+    }
+
+    #[test]
+    fn modifiers_stay_held_between_keys() {
+        let shifted = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::SHIFT);
+        let plain = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+        assert_eq!(
+            type_keys(&[shifted('A'), shifted('B'), plain('c')]),
+            [0x0012, 0x001C, 0x011C, 0x0032, 0x0132, 0x0112, 0x0021, 0x0121]
+        );
+    }
+}
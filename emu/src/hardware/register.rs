@@ -2,12 +2,20 @@
 use core::ops::Index;
 use std::ops::IndexMut;
 
+/// Register aliases, as used by the assembler.
+pub const REGISTER_NAMES: [&str; 16] = [
+    "ar", "p0", "p1", "p2", "p3", "v0", "t0", "t1", "t2", "t3", "t4", "t5", "isr", "sp", "sr",
+    "pc",
+];
+
 pub enum StatusRegisterFlag {
     X,
     Z,
     N,
     C,
     V,
+    /// Set while servicing an interrupt, no further interrupts are taken.
+    I,
 }
 
 impl StatusRegisterFlag {
@@ -18,6 +26,7 @@ impl StatusRegisterFlag {
             StatusRegisterFlag::N => 0x0004,
             StatusRegisterFlag::C => 0x0008,
             StatusRegisterFlag::V => 0x0010,
+            StatusRegisterFlag::I => 0x0020,
         }
     }
 }
@@ -26,6 +35,7 @@ impl StatusRegisterFlag {
    if (set_VC) SR[4:3] <= {V, C};
    SR[2:0] <= {N, Z, X};
 */
+#[repr(C)]
 pub struct StatusRegister {
     pub sr: u16,
 }
@@ -43,6 +53,7 @@ impl StatusRegister {
         }
     }
 }
+#[repr(C)]
 pub struct Registers {
     pub general: [u16; 12],
     // # r12 - ISR
@@ -55,6 +66,35 @@ pub struct Registers {
     pub pc: u16,
 }
 
+impl Registers {
+    pub fn new() -> Registers {
+        Registers {
+            general: [0; 12],
+            isr: 0,
+            sp: 0xBFFF,
+            sr: StatusRegister { sr: 0 },
+            pc: 0x0000,
+        }
+    }
+
+    /// All sixteen registers, in encoding order.
+    pub fn values(&self) -> [u16; 16] {
+        std::array::from_fn(|r| self[r as u16])
+    }
+
+    pub fn set_values(&mut self, values: &[u16; 16]) {
+        for (r, val) in values.iter().enumerate() {
+            self[r as u16] = *val;
+        }
+    }
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Registers::new()
+    }
+}
+
 impl Index<u16> for Registers {
     type Output = u16;
 
@@ -10,32 +10,14 @@ use crossterm::{
     style::{Color, Colors, Print, SetColors},
 };
 
-const COLORS: [Color; 8] = [
-    Color::Rgb { r: 0, g: 0, b: 0 },
-    Color::Rgb { r: 0, g: 0, b: 255 },
-    Color::Rgb { r: 0, g: 255, b: 0 },
-    Color::Rgb {
-        r: 0,
-        g: 255,
-        b: 255,
-    },
-    Color::Rgb { r: 255, g: 0, b: 0 },
-    Color::Rgb {
-        r: 255,
-        g: 0,
-        b: 255,
-    },
-    Color::Rgb {
-        r: 255,
-        g: 255,
-        b: 0,
-    },
-    Color::Rgb {
-        r: 255,
-        g: 255,
-        b: 255,
-    },
-];
+use crate::screen::cast::Cast;
+use crate::screen::PALETTE;
+
+/// The terminal colour for palette entry `i`.
+fn color(i: usize) -> Color {
+    let [r, g, b] = PALETTE[i];
+    Color::Rgb { r, g, b }
+}
 
 pub struct Vga<'a> {
     width: usize,
@@ -48,6 +30,8 @@ pub struct Vga<'a> {
     prev_time: SystemTime,
     running_count: &'a AtomicU64,
     running_frame_count: usize,
+
+    cast: Option<Cast>,
 }
 
 impl <'a> Vga<'a> {
@@ -61,9 +45,15 @@ impl <'a> Vga<'a> {
             prev_time: SystemTime::now(),
             running_count,
             running_frame_count: 0,
+            cast: None,
         }
     }
 
+    /// Records every frame drawn from now on to `cast`.
+    pub fn record(&mut self, cast: Cast) {
+        self.cast = Some(cast);
+    }
+
     pub fn reset(&mut self) {
        { 
             execute!(self.stdout, Hide,)
@@ -119,7 +109,7 @@ impl <'a> Vga<'a> {
                     first = false;
                     prevbg = bg;
                     prevfg = fg;
-                    self.stdout.queue(SetColors(Colors::new(COLORS[fg], COLORS[bg]))).unwrap();
+                    self.stdout.queue(SetColors(Colors::new(color(fg), color(bg)))).unwrap();
                 }
 
                 self.stdout.queue(Print(Self::char_of(val & 0x00FF))).unwrap();
@@ -128,6 +118,11 @@ impl <'a> Vga<'a> {
 
         self.stdout.flush().unwrap();
 
+        if let Some(cast) = &mut self.cast {
+            cast.frame(self.vram)
+                .expect("Something went wrong writing to the cast file!");
+        }
+
         self.running_frame_count += 1;
 
         let now: SystemTime = SystemTime::now();
@@ -155,5 +150,10 @@ impl <'a> Vga<'a> {
             self.flush_page();
         }
         self.flush_page();
+
+        if let Some(cast) = &mut self.cast {
+            cast.flush()
+                .expect("Something went wrong writing to the cast file!");
+        }
     }
 }
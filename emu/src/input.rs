@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+
+use emu::{Cpu, Error};
+
+use crate::args::Options;
+
+/*
+ *  Recordings are text, one key per line:
+ *
+ *  # toast input recording
+ *  1520 0x001c                   -- instruction count the key arrived at, scan code
+ *  1520 0x011c                   -- a release, see keyboard::RELEASED
+ */
+
+const HEADER: &str = "# toast input recording";
+
+/// Where keys come from.
+pub enum Input {
+    /// The key thread, nothing is logged.
+    Live,
+    /// The key thread, logging every key as it arrives in the keyboard fifo.
+    Record(File),
+    /// Keys read back from a recording, with no key thread running.
+    Replay(VecDeque<(u64, u16)>),
+}
+
+fn parse_line(line: &str) -> Result<(u64, u16), String> {
+    let bad = || format!("Bad input recording line {line}");
+    let (count, code) = line.split_once(' ').ok_or_else(bad)?;
+    let count = count.parse::<u64>().map_err(|_| bad())?;
+    let code = code.strip_prefix("0x").ok_or_else(bad)?;
+    let code = u16::from_str_radix(code, 16).map_err(|_| bad())?;
+    Ok((count, code))
+}
+
+impl Input {
+    pub fn from_options(opts: &Options) -> Result<Input, Error> {
+        if !opts.replay.is_empty() {
+            let text = fs::read_to_string(&opts.replay).map_err(|e| {
+                Error::Io(format!(
+                    "Could not read input recording {}: {e}",
+                    opts.replay
+                ))
+            })?;
+            let events = text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(parse_line)
+                .collect::<Result<_, _>>()
+                .map_err(Error::Usage)?;
+            return Ok(Input::Replay(events));
+        }
+
+        if !opts.record.is_empty() {
+            let mut file = File::create(&opts.record).map_err(|e| {
+                Error::Io(format!(
+                    "Could not create input recording {}: {e}",
+                    opts.record
+                ))
+            })?;
+            writeln!(file, "{HEADER}")
+                .map_err(|e| Error::Io(format!("Could not write input recording: {e}")))?;
+            return Ok(Input::Record(file));
+        }
+
+        Ok(Input::Live)
+    }
+
+    /// Hands the keyboard every recorded key that is due. Call before every step.
+    pub fn before_step(&mut self, cpu: &mut Cpu) {
+        match self {
+            Input::Replay(events) => {
+                while events.front().is_some_and(|(at, _)| *at <= cpu.count()) {
+                    let (_, code) = events.pop_front().unwrap();
+                    cpu.mem.key_fifo().receive(code);
+                }
+            }
+            Input::Record(_) => cpu.mem.key_fifo().log_keys(),
+            Input::Live => (),
+        }
+    }
+
+    /// Logs the keys that arrived during the step. Call after every step.
+    pub fn after_step(&mut self, cpu: &Cpu) -> Result<(), Error> {
+        if let Input::Record(file) = self {
+            for code in cpu.mem.key_fifo().take_log() {
+                // not buffered, ctrl-c exits the process without unwinding
+                writeln!(file, "{} {code:#06x}", cpu.count())
+                    .map_err(|e| Error::Io(format!("Could not write input recording: {e}")))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use std::ptr;
+
+use libc::{
+    c_void, mmap, mprotect, munmap, sysconf, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_EXEC,
+    PROT_READ, PROT_WRITE, _SC_PAGESIZE,
+};
+
+/// An mmap'd region that translated blocks are appended to. Pages are only ever writable
+/// while a block is being copied in, and are flipped back to read + execute afterwards.
+pub struct ExecBuffer {
+    base: *mut u8,
+    cap: usize,
+    len: usize,
+    page: usize,
+}
+
+impl ExecBuffer {
+    pub fn new(cap: usize) -> Result<ExecBuffer, String> {
+        let base = unsafe {
+            mmap(
+                ptr::null_mut(),
+                cap,
+                PROT_READ | PROT_EXEC,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if base == MAP_FAILED {
+            return Err(format!(
+                "Could not map {cap} bytes of executable memory: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        let page = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+        Ok(ExecBuffer { base: base as *mut u8, cap, len: 0, page })
+    }
+
+    fn protect(&self, start: usize, end: usize, prot: i32) -> Result<(), String> {
+        let start = start / self.page * self.page;
+        let end = end.div_ceil(self.page) * self.page;
+        let res = unsafe { mprotect(self.base.add(start) as *mut c_void, end - start, prot) };
+        if res != 0 {
+            return Err(format!(
+                "Could not change protection of jit pages: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether `len` more bytes of code fit.
+    pub fn fits(&self, len: usize) -> bool {
+        self.len + len <= self.cap
+    }
+
+    /// Starts over at the beginning of the buffer. The code pushed so far gets overwritten, so
+    /// none of it may run again.
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+
+    /// Copies `code` into the buffer and returns a pointer to its first byte.
+    pub fn push(&mut self, code: &[u8]) -> Result<*const u8, String> {
+        let start = self.len;
+        let end = start + code.len();
+        if end > self.cap {
+            return Err(format!("JIT code buffer of {} bytes is full", self.cap));
+        }
+
+        self.protect(start, end, PROT_READ | PROT_WRITE)?;
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), self.base.add(start), code.len()) };
+        self.protect(start, end, PROT_READ | PROT_EXEC)?;
+
+        self.len = end;
+        Ok(unsafe { self.base.add(start) })
+    }
+}
+
+impl Drop for ExecBuffer {
+    fn drop(&mut self) {
+        unsafe { munmap(self.base as *mut c_void, self.cap) };
+    }
+}
@@ -1,3 +1,542 @@
-pub fn jit(_rom: Vec<u16>) -> Result<(), String> {
-    Ok(())
-}
\ No newline at end of file
+mod exec;
+mod x86;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use emu::devices::Devices;
+use emu::hardware::def::*;
+use emu::hardware::register::Registers;
+use emu::loader::Program;
+use emu::cpu::{alu, interrupt, trap};
+use emu::error::Fault;
+use emu::symbols::Symbols;
+use emu::{Cpu, Error};
+
+use crate::args::Options;
+use crate::emulator;
+
+use self::exec::ExecBuffer;
+use self::x86::{Alu, Cond, Emitter, Reg, Shift};
+
+const CODE_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+const MAX_BLOCK_LEN: usize = 64;
+
+// Values returned by a translated block
+const EXIT_CONTINUE: u32 = 0;
+const EXIT_HALT: u32 = 1;
+const EXIT_MEMORY: u32 = 2;
+const EXIT_BAD_ALU: u32 = 3;
+const EXIT_ILLEGAL: u32 = 4;
+
+// Offsets of registers inside the context. `Registers` is repr(C), so r0 to r15 are laid out
+// back to back at the start of `Context`.
+const SP: u8 = 26;
+const SR: u8 = 28;
+const PC: u8 = 30;
+const SCRATCH: u8 = 32;
+
+const fn reg(r: u16) -> u8 {
+    (r * 2) as u8
+}
+
+/// State shared between translated code and the memory helpers it calls.
+#[repr(C)]
+struct Context {
+    registers: Registers,
+    /// Holds a value read by an instruction that can still fault, see RTI.
+    scratch: u16,
+    mem: *mut Devices,
+    error: Option<Error>,
+}
+
+extern "sysv64" fn read_helper(ctx: *mut Context, addr: u32) -> u32 {
+    let ctx = unsafe { &mut *ctx };
+    match unsafe { (*ctx.mem).read(addr as u16) } {
+        Ok(val) => val as u32,
+        Err(e) => {
+            ctx.error = Some(e);
+            u32::MAX
+        }
+    }
+}
+
+extern "sysv64" fn write_helper(ctx: *mut Context, addr: u32, val: u32) -> u32 {
+    let ctx = unsafe { &mut *ctx };
+    match unsafe { (*ctx.mem).write(addr as u16, val as u16) } {
+        Ok(()) => 0,
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+extern "sysv64" fn alu_helper(ctx: *mut Context, a: u32, op: u32, b: u32) -> u32 {
+    let ctx = unsafe { &mut *ctx };
+    alu(op as u16, a as i32, b as i32, &mut ctx.registers.sr)
+        .expect("alu_helper is only called for defined operations") as u32
+}
+
+type BlockFn = extern "sysv64" fn(*mut Context) -> u32;
+
+#[derive(Clone, Copy)]
+struct Block {
+    entry: BlockFn,
+    len: u64,
+}
+
+struct Translator {
+    rom: Vec<u16>,
+    buffer: ExecBuffer,
+    blocks: Vec<Option<Block>>,
+}
+
+impl Translator {
+    /// A translator whose blocks share `buffer_size` bytes of code.
+    fn new(rom: Vec<u16>, buffer_size: usize) -> Result<Translator, String> {
+        Ok(Translator {
+            blocks: vec![None; rom.len()],
+            rom,
+            buffer: ExecBuffer::new(buffer_size)?,
+        })
+    }
+
+    fn block(&mut self, pc: u16) -> Result<Block, String> {
+        if let Some(block) = self.blocks[pc as usize] {
+            return Ok(block);
+        }
+
+        let mut e = Emitter::default();
+        e.push_rbx();
+        e.mov_rbx_rdi();
+
+        let mut len = 0;
+        let mut addr = pc as usize;
+        loop {
+            let ends_block = translate(&mut e, addr as u16, self.rom[addr]);
+            len += 1;
+            addr += 1;
+
+            if ends_block {
+                break;
+            }
+            if len == MAX_BLOCK_LEN || addr == self.rom.len() {
+                e.store16_imm(PC, addr as u16);
+                exit(&mut e, EXIT_CONTINUE);
+                break;
+            }
+        }
+
+        if !self.buffer.fits(e.code.len()) {
+            // blocks always return to dispatch instead of jumping into each other, so between
+            // two blocks none is running and all of them can be dropped and translated again
+            self.blocks.fill(None);
+            self.buffer.reset();
+        }
+        let code = self.buffer.push(&e.code)?;
+        let block = Block {
+            entry: unsafe { std::mem::transmute::<*const u8, BlockFn>(code) },
+            len: len as u64,
+        };
+        self.blocks[pc as usize] = Some(block);
+        Ok(block)
+    }
+
+    fn execute(&mut self, cpu: &mut Cpu) -> Result<(), Error> {
+        let irq = cpu.irq_handle();
+        let running_count = cpu.count_handle();
+        let mut ctx = Context {
+            registers: Registers::new(),
+            scratch: 0,
+            mem: &mut cpu.mem,
+            error: None,
+        };
+        std::mem::swap(&mut ctx.registers, &mut cpu.registers);
+
+        let result = self.dispatch(&mut ctx, &irq, &running_count);
+
+        std::mem::swap(&mut ctx.registers, &mut cpu.registers);
+        result
+    }
+
+    fn fault(&self, ctx: &Context, inst: Option<u16>) -> Fault {
+        Fault {
+            pc: ctx.registers.pc,
+            inst,
+            registers: ctx.registers.values(),
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        ctx: &mut Context,
+        irq: &Mutex<bool>,
+        running_count: &AtomicU64,
+    ) -> Result<(), Error> {
+        loop {
+            interrupt(&mut ctx.registers, unsafe { &mut *ctx.mem }, irq)?;
+
+            let pc = ctx.registers.pc;
+            if pc as usize >= self.rom.len() {
+                return Err(Error::memory("JIT cannot execute code outside of rom")
+                    .at(|| self.fault(ctx, None)));
+            }
+
+            let block = self.block(pc).map_err(Error::Io)?;
+            let status = (block.entry)(ctx);
+
+            let ran = match status {
+                EXIT_CONTINUE | EXIT_HALT => block.len,
+                // faulting blocks stop with pc on the faulting instruction, the ones before it ran
+                _ => ctx.registers.pc.wrapping_sub(pc) as u64,
+            };
+            running_count.fetch_add(ran, Ordering::Relaxed);
+            unsafe { (*ctx.mem).tick(ran) };
+
+            let e = match status {
+                EXIT_CONTINUE => continue,
+                EXIT_HALT => return Ok(()),
+                _ => self.error(ctx, status),
+            };
+            // faults leave the registers as they were before the instruction, like the
+            // interpreter's rollback, so the handler sees the same state
+            if !trap(&mut ctx.registers, unsafe { &mut *ctx.mem }, &e) {
+                return Err(e);
+            }
+        }
+    }
+
+    /// The error for a block that stopped with a fault `status` on the instruction at pc.
+    fn error(&self, ctx: &mut Context, status: u32) -> Error {
+        let inst = self.rom[ctx.registers.pc as usize];
+        match status {
+            EXIT_MEMORY => {
+                let e = ctx.error.take().unwrap_or_else(|| Error::memory("Memory error"));
+                e.at(|| self.fault(ctx, Some(inst)))
+            }
+            EXIT_BAD_ALU => Error::BadAluOp {
+                op: inst & 0x000F,
+                fault: self.fault(ctx, Some(inst)),
+            },
+            EXIT_ILLEGAL => Error::IllegalInstruction {
+                fault: self.fault(ctx, Some(inst)),
+            },
+            _ => unreachable!("jit block returned unknown status {status}"),
+        }
+    }
+}
+
+fn exit(e: &mut Emitter, status: u32) {
+    e.mov_imm(Reg::Eax, status);
+    e.pop_rbx();
+    e.ret();
+}
+
+/// Calls `read_helper` with the address in esi, leaving the value in eax.
+fn emit_read(e: &mut Emitter) {
+    e.mov_rdi_rbx();
+    e.call(read_helper as *const () as u64);
+    e.cmp_imm(Reg::Eax, u32::MAX);
+    let ok = e.jcc(Cond::Nz);
+    exit(e, EXIT_MEMORY);
+    e.bind(ok);
+}
+
+/// Calls `write_helper` with the address in esi and the value in edx.
+fn emit_write(e: &mut Emitter) {
+    e.mov_rdi_rbx();
+    e.call(write_helper as *const () as u64);
+    e.test(Reg::Eax);
+    let ok = e.jcc(Cond::Z);
+    exit(e, EXIT_MEMORY);
+    e.bind(ok);
+}
+
+/// Sets `mask` in edx when the preceding test leaves the `skip` condition false.
+fn set_flag_unless(e: &mut Emitter, skip: Cond, mask: u32) {
+    let label = e.jcc(skip);
+    e.alu_imm(Alu::Or, Reg::Edx, mask);
+    e.bind(label);
+}
+
+/// Mirrors `cpu::alu`: a in esi, b in ecx, the result ends up in eax and the flags in sr.
+/// Returns false for alu operations that do not exist.
+fn emit_alu(e: &mut Emitter, op: u16) -> bool {
+    match op {
+        0x0 => {
+            e.mov(Reg::Eax, Reg::Esi);
+            e.not(Reg::Eax);
+        }
+        0x1 | 0x2 | 0x3 | 0x4 | 0x5 | 0x7 => {
+            let alu = match op {
+                0x1 => Alu::And,
+                0x2 => Alu::Or,
+                0x3 => Alu::Xor,
+                0x4 => Alu::Add,
+                _ => Alu::Sub,
+            };
+            e.mov(Reg::Eax, Reg::Esi);
+            e.alu(alu, Reg::Eax, Reg::Ecx);
+        }
+        0x6 => e.mov(Reg::Eax, Reg::Ecx),
+        0x8 => {
+            e.alu_imm(Alu::And, Reg::Ecx, 15);
+            e.mov(Reg::Eax, Reg::Esi);
+            e.shift_cl(Shift::Shr, Reg::Eax);
+        }
+        0x9 => {
+            e.alu_imm(Alu::And, Reg::Ecx, 15);
+            e.sign_extend16(Reg::Eax, Reg::Esi);
+            e.shift_cl(Shift::Sar, Reg::Eax);
+        }
+        0xA => {
+            e.mov(Reg::Eax, Reg::Esi);
+            e.shift_cl(Shift::Shl, Reg::Eax);
+        }
+        0xB..=0xE => {
+            // multiply and divide share the interpreter's flag semantics through `alu_helper`
+            e.mov_imm(Reg::Edx, op as u32);
+            e.mov_rdi_rbx();
+            e.call(alu_helper as *const () as u64);
+            return true;
+        }
+        _ => return false,
+    }
+
+    e.mov_imm(Reg::Edx, 0);
+    e.test_imm(Reg::Eax, 0x8000);
+    set_flag_unless(e, Cond::Z, 0x0004);
+    e.test(Reg::Eax);
+    set_flag_unless(e, Cond::Nz, 0x0002);
+    e.cmp_imm(Reg::Eax, 0xFFFF);
+    set_flag_unless(e, Cond::Nz, 0x0001);
+
+    let mut mask: u16 = 0x0007;
+    if matches!(op, 0x4 | 0x5 | 0x7) {
+        mask = 0x001F;
+
+        // V: sign of a differs from the result, and a and b agree (add) or differ (sub)
+        e.mov(Reg::Edi, Reg::Esi);
+        e.alu(Alu::Xor, Reg::Edi, Reg::Eax);
+        e.alu(Alu::Xor, Reg::Esi, Reg::Ecx);
+        if op == 0x4 {
+            e.not(Reg::Esi);
+        }
+        e.alu(Alu::And, Reg::Edi, Reg::Esi);
+        e.test_imm(Reg::Edi, 0x8000);
+        set_flag_unless(e, Cond::Z, 0x0010);
+
+        // C: bit 16 of the 32 bit result
+        e.test_imm(Reg::Eax, 0x10000);
+        set_flag_unless(e, Cond::Z, 0x0008);
+    }
+
+    e.and16_imm(SR, !mask);
+    e.or16(SR, Reg::Edx);
+    true
+}
+
+/// Emits host code for the instruction `inst` at `pc`. Returns true when the instruction ends
+/// the block, in which case the emitted code has already set pc and returned.
+fn translate(e: &mut Emitter, pc: u16, inst: u16) -> bool {
+    let opcode: u16 = (inst & 0xF000) >> 12;
+    let r1: u16 = (inst & 0x0F00) >> 8;
+    let r2: u16 = (inst & 0x00F0) >> 4;
+    let imm8: u16 = inst & 0x00FF;
+    let alu_imm4: u16 = r2;
+    let alu_op: u16 = inst & 0x000F;
+    let load_offset: u16 = alu_op;
+    let jmp_op: u16 = alu_op;
+
+    // pc is kept up to date in memory, so reads of r15 and error reporting see this instruction
+    e.store16_imm(PC, pc);
+
+    let writes_pc = match opcode {
+        LOAD => {
+            e.load16(Reg::Esi, reg(r2));
+            e.alu_imm(Alu::Add, Reg::Esi, load_offset as u32);
+            e.alu_imm(Alu::And, Reg::Esi, 0xFFFF);
+            emit_read(e);
+            e.store16(reg(r1), Reg::Eax);
+            r1 == 15
+        }
+        STR => {
+            e.load16(Reg::Esi, reg(r1));
+            e.alu_imm(Alu::Add, Reg::Esi, load_offset as u32);
+            e.alu_imm(Alu::And, Reg::Esi, 0xFFFF);
+            e.load16(Reg::Edx, reg(r2));
+            emit_write(e);
+            false
+        }
+        IMOV => {
+            let imm = imm8 | if imm8 & 0x0080 == 0 { 0x0000 } else { 0xFF00 };
+            e.store16_imm(reg(r1), imm);
+            r1 == 15
+        }
+        IMOH => {
+            e.store8_imm(reg(r1) + 1, imm8 as u8);
+            r1 == 15
+        }
+        PUSH => {
+            // r1 is only decremented once the write went through, so a fault leaves it alone
+            e.load16(Reg::Esi, reg(r1));
+            e.alu_imm(Alu::Add, Reg::Esi, 0xFFFF);
+            e.alu_imm(Alu::And, Reg::Esi, 0xFFFF);
+            if r2 == r1 {
+                e.mov(Reg::Edx, Reg::Esi);
+            } else {
+                e.load16(Reg::Edx, reg(r2));
+            }
+            emit_write(e);
+            e.add16_imm(reg(r1), -1);
+            r1 == 15
+        }
+        POP => {
+            e.load16(Reg::Esi, reg(r2));
+            emit_read(e);
+            e.store16(reg(r1), Reg::Eax);
+            e.add16_imm(reg(r2), 1);
+            r1 == 15 || r2 == 15
+        }
+        HALT => {
+            e.store16_imm(PC, pc.wrapping_add(1));
+            exit(e, EXIT_HALT);
+            return true;
+        }
+        ALU | IALU => {
+            e.load16(Reg::Esi, reg(r1));
+            if opcode == ALU {
+                e.load16(Reg::Ecx, reg(r2));
+            } else {
+                e.mov_imm(Reg::Ecx, alu_imm4 as u32);
+            }
+
+            if !emit_alu(e, alu_op) {
+                exit(e, EXIT_BAD_ALU);
+                return true;
+            }
+
+            if alu_op != 0x7 {
+                e.store16(reg(r1), Reg::Eax);
+            }
+            r1 == 15 && alu_op != 0x7
+        }
+        JMP => {
+            let l: bool = r2 & 1 != 0;
+            let r: bool = r2 & 2 != 0;
+
+            let not_taken = match jmp_op {
+                0 => None,
+                1 => {
+                    e.test16_imm(SR, 0x0002);
+                    Some(e.jcc(Cond::Z))
+                }
+                2 => {
+                    e.test16_imm(SR, 0x0002);
+                    Some(e.jcc(Cond::Nz))
+                }
+                3 => {
+                    e.test16_imm(SR, 0x0004);
+                    Some(e.jcc(Cond::Z))
+                }
+                4 => {
+                    e.test16_imm(SR, 0x0006);
+                    Some(e.jcc(Cond::Nz))
+                }
+                _ => {
+                    e.store16_imm(PC, pc.wrapping_add(1));
+                    exit(e, EXIT_CONTINUE);
+                    return true;
+                }
+            };
+
+            if r {
+                e.load16(Reg::Esi, SP);
+                emit_read(e);
+                e.store16(PC, Reg::Eax);
+                e.add16_imm(SP, 1);
+            } else {
+                if l {
+                    e.load16(Reg::Esi, SP);
+                    e.alu_imm(Alu::Add, Reg::Esi, 0xFFFF);
+                    e.alu_imm(Alu::And, Reg::Esi, 0xFFFF);
+                    e.mov_imm(Reg::Edx, pc.wrapping_add(1) as u32);
+                    emit_write(e);
+                    e.add16_imm(SP, -1);
+                }
+                e.load16(Reg::Eax, reg(r1));
+                e.store16(PC, Reg::Eax);
+            }
+            exit(e, EXIT_CONTINUE);
+
+            if let Some(label) = not_taken {
+                e.bind(label);
+                e.store16_imm(PC, pc.wrapping_add(1));
+                exit(e, EXIT_CONTINUE);
+            }
+            return true;
+        }
+        RTI => {
+            // both words are read before sr and sp change, so a fault leaves them alone
+            e.load16(Reg::Esi, SP);
+            emit_read(e);
+            e.store16(SCRATCH, Reg::Eax);
+            e.load16(Reg::Esi, SP);
+            e.alu_imm(Alu::Add, Reg::Esi, 1);
+            e.alu_imm(Alu::And, Reg::Esi, 0xFFFF);
+            emit_read(e);
+            e.store16(PC, Reg::Eax);
+            e.load16(Reg::Eax, SCRATCH);
+            e.store16(SR, Reg::Eax);
+            e.add16_imm(SP, 2);
+            exit(e, EXIT_CONTINUE);
+            return true;
+        }
+        _ => {
+            exit(e, EXIT_ILLEGAL);
+            return true;
+        }
+    };
+
+    if writes_pc {
+        // the interpreter increments pc after every instruction, including ones that write it
+        e.add16_imm(PC, 1);
+        exit(e, EXIT_CONTINUE);
+    }
+    writes_pc
+}
+
+/// Runs `rom` by translating basic blocks of toast instructions into x86-64 code.
+#[cfg(target_arch = "x86_64")]
+pub fn jit(program: Program, opts: &Options, symbols: &Arc<Symbols>) -> Result<(), Error> {
+    let mut translator = Translator::new(program.rom.clone(), CODE_BUFFER_SIZE).map_err(Error::Io)?;
+    emulator::run(program, opts, symbols, |cpu| translator.execute(cpu))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn jit(_program: Program, _opts: &Options, _symbols: &Arc<Symbols>) -> Result<(), Error> {
+    Err(Error::Usage("JIT mode is only supported on x86-64 hosts".to_string()))
+}
+
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_code_buffer_is_flushed() {
+        // r1 += 1 a few thousand times in 64 instruction blocks, looped over three times, far
+        // more code than fits in 64 KiB
+        const ADDS: u16 = 3000;
+        let mut rom = vec![0x2203, 0x2302]; // imov r2 3, imov r3 2
+        rom.extend((0..ADDS).map(|_| 0x9114)); // iadd r1 1
+        rom.extend([0x9215, 0xA302, 0x7000]); // isub r2 1, jnz r3, halt
+
+        let mut translator = Translator::new(rom.clone(), 64 * 1024).unwrap();
+        let mut cpu = Cpu::new(rom);
+        translator.execute(&mut cpu).unwrap();
+        assert_eq!(cpu.registers.values()[1], 3 * ADDS);
+        assert_eq!(cpu.registers.values()[2], 0);
+    }
+}
@@ -0,0 +1,212 @@
+// A tiny x86-64 encoder, only covering the instructions the translator emits.
+// Every memory operand is `[rbx + disp8]`, since rbx always holds the context pointer.
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Eax = 0,
+    Ecx = 1,
+    Edx = 2,
+    Esi = 6,
+    Edi = 7,
+}
+
+#[derive(Clone, Copy)]
+pub enum Alu {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+}
+
+#[derive(Clone, Copy)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+#[derive(Clone, Copy)]
+pub enum Cond {
+    Z = 0x4,
+    Nz = 0x5,
+}
+
+/// A forward jump whose rel8 displacement still needs to be patched.
+pub struct Label(usize);
+
+#[derive(Default)]
+pub struct Emitter {
+    pub code: Vec<u8>,
+}
+
+impl Emitter {
+    fn byte(&mut self, b: u8) {
+        self.code.push(b);
+    }
+
+    fn bytes(&mut self, bs: &[u8]) {
+        self.code.extend_from_slice(bs);
+    }
+
+    fn mem(&mut self, reg: u8, disp: u8) {
+        // mod = 01 (disp8), rm = 011 (rbx)
+        self.byte(0x43 | (reg << 3));
+        self.byte(disp);
+    }
+
+    pub fn push_rbx(&mut self) {
+        self.byte(0x53);
+    }
+
+    pub fn pop_rbx(&mut self) {
+        self.byte(0x5B);
+    }
+
+    pub fn ret(&mut self) {
+        self.byte(0xC3);
+    }
+
+    /// mov rbx, rdi
+    pub fn mov_rbx_rdi(&mut self) {
+        self.bytes(&[0x48, 0x89, 0xFB]);
+    }
+
+    /// mov rdi, rbx
+    pub fn mov_rdi_rbx(&mut self) {
+        self.bytes(&[0x48, 0x89, 0xDF]);
+    }
+
+    /// call an absolute address through rax
+    pub fn call(&mut self, addr: u64) {
+        self.bytes(&[0x48, 0xB8]);
+        self.bytes(&addr.to_le_bytes());
+        self.bytes(&[0xFF, 0xD0]);
+    }
+
+    /// movzx dst, word [rbx + disp]
+    pub fn load16(&mut self, dst: Reg, disp: u8) {
+        self.bytes(&[0x0F, 0xB7]);
+        self.mem(dst as u8, disp);
+    }
+
+    /// mov word [rbx + disp], src
+    pub fn store16(&mut self, disp: u8, src: Reg) {
+        self.bytes(&[0x66, 0x89]);
+        self.mem(src as u8, disp);
+    }
+
+    /// mov word [rbx + disp], imm
+    pub fn store16_imm(&mut self, disp: u8, imm: u16) {
+        self.bytes(&[0x66, 0xC7]);
+        self.mem(0, disp);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// mov byte [rbx + disp], imm
+    pub fn store8_imm(&mut self, disp: u8, imm: u8) {
+        self.byte(0xC6);
+        self.mem(0, disp);
+        self.byte(imm);
+    }
+
+    /// add word [rbx + disp], imm8 (sign extended)
+    pub fn add16_imm(&mut self, disp: u8, imm: i8) {
+        self.bytes(&[0x66, 0x83]);
+        self.mem(0, disp);
+        self.byte(imm as u8);
+    }
+
+    /// and word [rbx + disp], imm
+    pub fn and16_imm(&mut self, disp: u8, imm: u16) {
+        self.bytes(&[0x66, 0x81]);
+        self.mem(4, disp);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// or word [rbx + disp], src
+    pub fn or16(&mut self, disp: u8, src: Reg) {
+        self.bytes(&[0x66, 0x09]);
+        self.mem(src as u8, disp);
+    }
+
+    /// test word [rbx + disp], imm
+    pub fn test16_imm(&mut self, disp: u8, imm: u16) {
+        self.bytes(&[0x66, 0xF7]);
+        self.mem(0, disp);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// mov dst, imm
+    pub fn mov_imm(&mut self, dst: Reg, imm: u32) {
+        self.byte(0xB8 | dst as u8);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// mov dst, src
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.bytes(&[0x89, 0xC0 | ((src as u8) << 3) | dst as u8]);
+    }
+
+    /// movsx dst, src16
+    pub fn sign_extend16(&mut self, dst: Reg, src: Reg) {
+        self.bytes(&[0x0F, 0xBF, 0xC0 | ((dst as u8) << 3) | src as u8]);
+    }
+
+    /// (op) dst, src
+    pub fn alu(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.bytes(&[op as u8, 0xC0 | ((src as u8) << 3) | dst as u8]);
+    }
+
+    /// (op) dst, imm
+    pub fn alu_imm(&mut self, op: Alu, dst: Reg, imm: u32) {
+        let ext: u8 = match op {
+            Alu::Add => 0,
+            Alu::Or => 1,
+            Alu::And => 4,
+            Alu::Sub => 5,
+            Alu::Xor => 6,
+        };
+        self.bytes(&[0x81, 0xC0 | (ext << 3) | dst as u8]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// not dst
+    pub fn not(&mut self, dst: Reg) {
+        self.bytes(&[0xF7, 0xD0 | dst as u8]);
+    }
+
+    /// (shift) dst, cl
+    pub fn shift_cl(&mut self, op: Shift, dst: Reg) {
+        self.bytes(&[0xD3, 0xC0 | ((op as u8) << 3) | dst as u8]);
+    }
+
+    /// test dst, imm
+    pub fn test_imm(&mut self, dst: Reg, imm: u32) {
+        self.bytes(&[0xF7, 0xC0 | dst as u8]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// cmp dst, imm
+    pub fn cmp_imm(&mut self, dst: Reg, imm: u32) {
+        self.bytes(&[0x81, 0xF8 | dst as u8]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// test dst, dst
+    pub fn test(&mut self, dst: Reg) {
+        self.bytes(&[0x85, 0xC0 | ((dst as u8) << 3) | dst as u8]);
+    }
+
+    /// j(cond) to a label that is bound later
+    pub fn jcc(&mut self, cond: Cond) -> Label {
+        self.bytes(&[0x70 | cond as u8, 0]);
+        Label(self.code.len())
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        let rel = self.code.len() - label.0;
+        assert!(rel <= i8::MAX as usize, "jit forward jump is too long");
+        self.code[label.0 - 1] = rel as u8;
+    }
+}
@@ -0,0 +1,16 @@
+//! The toast cpu emulator core. `Cpu` owns the registers and the memory bus and knows nothing
+//! about terminals or threads, so anything can drive it one `step` at a time. The `emu` binary
+//! with its crossterm display is one such front end.
+
+pub mod cpu;
+pub mod devices;
+pub mod disasm;
+pub mod error;
+pub mod hardware;
+pub mod loader;
+pub mod screen;
+pub mod snapshot;
+pub mod symbols;
+
+pub use cpu::{Cpu, Step};
+pub use error::Error;
@@ -0,0 +1,569 @@
+//! Rom and ram images. Images are read as (address, word) pairs relative to where they get
+//! loaded, and placed into a `Program` holding the initial rom and ram.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::hardware::def::{RAM_SIZE, ROM_SIZE};
+
+/// Words of an image as (address, value) pairs, relative to the address it is loaded at.
+pub type Words = Vec<(u32, u16)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Quartus memory initialization file.
+    Mif,
+    /// Intel HEX with word addresses and big endian words, the way Quartus writes 16 bit memories.
+    IntelHex,
+    /// Raw little endian words.
+    BinLe,
+    /// Raw big endian words.
+    BinBe,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mif" => Ok(Format::Mif),
+            "hex" => Ok(Format::IntelHex),
+            "bin-le" => Ok(Format::BinLe),
+            "bin-be" => Ok(Format::BinBe),
+            _ => Err(format!("Unknown image format {s}, expected mif, hex, bin-le or bin-be")),
+        }
+    }
+}
+
+impl Format {
+    /// Picks the format from the extension of `path`, raw `.bin` files are taken as little endian.
+    pub fn from_path(path: &str) -> Result<Format, String> {
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("mif") => Ok(Format::Mif),
+            Some("hex" | "ihex") => Ok(Format::IntelHex),
+            Some("bin") => Ok(Format::BinLe),
+            _ => Err(format!(
+                "Can not tell the format of {path} from its extension, give one of mif, hex, bin-le or bin-be"
+            )),
+        }
+    }
+}
+
+/// Parses an image in `format`.
+pub fn parse(data: &[u8], format: Format) -> Result<Words, String> {
+    match format {
+        Format::Mif => parse_mif(text(data)?),
+        Format::IntelHex => parse_hex(text(data)?),
+        Format::BinLe => parse_bin(data, u16::from_le_bytes),
+        Format::BinBe => parse_bin(data, u16::from_be_bytes),
+    }
+}
+
+fn text(data: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(data).map_err(|e| format!("not a text file: {e}"))
+}
+
+fn parse_bin(data: &[u8], word: fn([u8; 2]) -> u16) -> Result<Words, String> {
+    if !data.len().is_multiple_of(2) {
+        return Err(format!("{} bytes is not a whole number of 16 bit words", data.len()));
+    }
+    Ok(data
+        .chunks_exact(2)
+        .enumerate()
+        .map(|(i, pair)| (i as u32, word([pair[0], pair[1]])))
+        .collect())
+}
+
+/*
+ *  Intel HEX records, one per line:
+ *
+ *  :LLAAAATTDD..CC      -- byte count, address, record type, data, checksum
+ *
+ *  Addresses count words and every word is two data bytes, high byte first. Types 00 (data),
+ *  01 (end of file), 02 (extended segment address) and 04 (extended linear address) are used,
+ *  start address records are ignored.
+ */
+
+fn parse_hex(text: &str) -> Result<Words, String> {
+    let mut words = Vec::new();
+    let mut base: u32 = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let n = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let digits = line
+            .strip_prefix(':')
+            .ok_or_else(|| format!("line {n}: records start with a colon"))?;
+        if !digits.len().is_multiple_of(2) || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("line {n}: {digits} is not a string of hex bytes"));
+        }
+        let bytes: Vec<u8> = (0..digits.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&digits[at..at + 2], 16).unwrap())
+            .collect();
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(format!("line {n}: record length does not match its byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(format!("line {n}: bad checksum"));
+        }
+
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => {
+                if !data.len().is_multiple_of(2) {
+                    return Err(format!("line {n}: data records hold whole 16 bit words"));
+                }
+                for (k, pair) in data.chunks_exact(2).enumerate() {
+                    words.push((base + addr + k as u32, u16::from_be_bytes([pair[0], pair[1]])));
+                }
+            }
+            0x01 => return Ok(words),
+            0x02 | 0x04 if data.len() == 2 => {
+                let val = u16::from_be_bytes([data[0], data[1]]) as u32;
+                base = if bytes[3] == 0x02 { val << 4 } else { val << 16 };
+            }
+            0x02 | 0x04 => return Err(format!("line {n}: address records hold a single word")),
+            0x03 | 0x05 => (),
+            t => return Err(format!("line {n}: unsupported record type {t:02X}")),
+        }
+    }
+
+    Err("missing the end of file record".to_string())
+}
+
+/*
+ *  Memory initialization files, as written by the assembler and Quartus:
+ *
+ *  DEPTH = 32768;                -- header entries, all optional
+ *  WIDTH = 16;
+ *  ADDRESS_RADIX = HEX;          -- BIN, OCT, DEC, UNS or HEX
+ *  DATA_RADIX = HEX;
+ *  CONTENT
+ *  BEGIN
+ *  0000 : 2C08;                  -- a word
+ *  0001 : 7000 7000 1234;        -- consecutive words
+ *  [0004..00FF] : 7000;          -- a range, filled by repeating the values
+ *  END;
+ *
+ *  Comments run from -- to the end of the line, or between two %.
+ */
+
+#[derive(Clone, Copy)]
+enum Radix {
+    Bin,
+    Oct,
+    Dec,
+    Uns,
+    Hex,
+}
+
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let bytes = text.as_bytes();
+    let mut line = 1;
+    let mut at = 0;
+
+    while at < bytes.len() {
+        let c = bytes[at];
+        let start = at;
+        match c {
+            b'\n' => {
+                line += 1;
+                at += 1;
+            }
+            _ if c.is_ascii_whitespace() => at += 1,
+            b'-' if bytes.get(at + 1) == Some(&b'-') => {
+                while at < bytes.len() && bytes[at] != b'\n' {
+                    at += 1;
+                }
+            }
+            b'%' => {
+                let opened = line;
+                at += 1;
+                while at < bytes.len() && bytes[at] != b'%' {
+                    if bytes[at] == b'\n' {
+                        line += 1;
+                    }
+                    at += 1;
+                }
+                if at == bytes.len() {
+                    return Err(format!("line {opened}: unterminated % comment"));
+                }
+                at += 1;
+            }
+            b'.' if bytes.get(at + 1) == Some(&b'.') => {
+                at += 2;
+                tokens.push(Token { text: &text[start..at], line });
+            }
+            b';' | b':' | b'=' | b'[' | b']' => {
+                at += 1;
+                tokens.push(Token { text: &text[start..at], line });
+            }
+            _ if c.is_ascii_alphanumeric() || c == b'_' || c == b'-' => {
+                // a leading minus sign for negative decimals
+                at += 1;
+                while at < bytes.len() && (bytes[at].is_ascii_alphanumeric() || bytes[at] == b'_') {
+                    at += 1;
+                }
+                tokens.push(Token { text: &text[start..at], line });
+            }
+            _ => {
+                let c = text[at..].chars().next().unwrap();
+                return Err(format!("line {line}: unexpected character {c:?}"));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct MifParser<'a> {
+    tokens: Vec<Token<'a>>,
+    at: usize,
+    depth: u32,
+    width: u32,
+    address_radix: Radix,
+    data_radix: Radix,
+}
+
+impl<'a> MifParser<'a> {
+    /// The line of the current token, or of the last one at the end of the file.
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.at)
+            .or(self.tokens.last())
+            .map_or(1, |t| t.line)
+    }
+
+    fn error(&self, msg: &str) -> String {
+        format!("line {}: {msg}", self.line())
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.at).map(|t| t.text)
+    }
+
+    fn next(&mut self, what: &str) -> Result<&'a str, String> {
+        match self.tokens.get(self.at) {
+            Some(t) => {
+                self.at += 1;
+                Ok(t.text)
+            }
+            None => Err(self.error(&format!("expected {what}, found the end of the file"))),
+        }
+    }
+
+    fn expect(&mut self, want: &str) -> Result<(), String> {
+        let line = self.line();
+        let got = self.next(want)?;
+        if !got.eq_ignore_ascii_case(want) {
+            return Err(format!("line {line}: expected {want}, found {got}"));
+        }
+        Ok(())
+    }
+
+    fn radix(&self, s: &str) -> Result<Radix, String> {
+        match s.to_ascii_uppercase().as_str() {
+            "BIN" => Ok(Radix::Bin),
+            "OCT" => Ok(Radix::Oct),
+            "DEC" => Ok(Radix::Dec),
+            "UNS" => Ok(Radix::Uns),
+            "HEX" => Ok(Radix::Hex),
+            _ => Err(self.error(&format!("unknown radix {s}"))),
+        }
+    }
+
+    fn decimal(&self, s: &str) -> Result<u32, String> {
+        s.parse::<u32>()
+            .map_err(|_| self.error(&format!("{s} is not a decimal number")))
+    }
+
+    /// Parses `s` in `radix`, negative decimals are two's complement in `bits`.
+    fn number(&self, s: &str, radix: Radix, bits: u32) -> Result<u32, String> {
+        let bad = || self.error(&format!("{s} is not a valid number"));
+        if s.starts_with('+') || (s.starts_with('-') && !matches!(radix, Radix::Dec)) {
+            return Err(bad());
+        }
+        let val: i64 = match radix {
+            Radix::Bin => i64::from_str_radix(s, 2).map_err(|_| bad())?,
+            Radix::Oct => i64::from_str_radix(s, 8).map_err(|_| bad())?,
+            Radix::Hex => i64::from_str_radix(s, 16).map_err(|_| bad())?,
+            Radix::Dec => s.parse::<i64>().map_err(|_| bad())?,
+            Radix::Uns => s.parse::<u64>().map_err(|_| bad())? as i64,
+        };
+        let limit = 1i64 << bits;
+        if val >= limit || val < -(limit / 2) {
+            return Err(self.error(&format!("{s} does not fit in {bits} bits")));
+        }
+        Ok((val & (limit - 1)) as u32)
+    }
+
+    fn address(&self, s: &str) -> Result<u32, String> {
+        let addr = self.number(s, self.address_radix, 32)?;
+        if addr >= self.depth {
+            return Err(self.error(&format!("address {s} is past the depth of {}", self.depth)));
+        }
+        Ok(addr)
+    }
+
+    fn header(&mut self) -> Result<(), String> {
+        loop {
+            let key = self.next("CONTENT")?;
+            if key.eq_ignore_ascii_case("CONTENT") {
+                return self.expect("BEGIN");
+            }
+            self.expect("=")?;
+            let val = self.next("a value")?;
+            match key.to_ascii_uppercase().as_str() {
+                "DEPTH" => {
+                    self.depth = self.decimal(val)?;
+                    if self.depth == 0 || self.depth > 0x10000 {
+                        return Err(self.error(&format!("DEPTH {val} does not fit the 16 bit address space")));
+                    }
+                }
+                "WIDTH" => {
+                    self.width = self.decimal(val)?;
+                    if self.width == 0 || self.width > 16 {
+                        return Err(self.error(&format!("WIDTH {val} does not fit in 16 bit words")));
+                    }
+                }
+                "ADDRESS_RADIX" => self.address_radix = self.radix(val)?,
+                "DATA_RADIX" => self.data_radix = self.radix(val)?,
+                _ => return Err(self.error(&format!("unknown header entry {key}"))),
+            }
+            self.expect(";")?;
+        }
+    }
+
+    fn content(&mut self) -> Result<Words, String> {
+        let mut words = Vec::new();
+        loop {
+            let line = self.line();
+            let first = self.next("END")?;
+            if first.eq_ignore_ascii_case("END") {
+                if self.peek() == Some(";") {
+                    self.at += 1;
+                }
+                if self.peek().is_some() {
+                    return Err(self.error("unexpected text after END"));
+                }
+                return Ok(words);
+            }
+
+            let range = if first == "[" {
+                let start = self.next("an address")?;
+                let start = self.address(start)?;
+                self.expect("..")?;
+                let end = self.next("an address")?;
+                let end = self.address(end)?;
+                self.expect("]")?;
+                if end < start {
+                    return Err(format!("line {line}: the range ends before it starts"));
+                }
+                Some((start, end))
+            } else {
+                None
+            };
+            let start = match range {
+                Some((start, _)) => start,
+                None => self.address(first)?,
+            };
+            self.expect(":")?;
+
+            // the values and the closing semicolon have to be on the line of the address
+            let mut values = Vec::new();
+            while self.tokens.get(self.at).is_some_and(|t| t.line == line && t.text != ";") {
+                let val = self.next("a value")?;
+                values.push(self.number(val, self.data_radix, self.width)? as u16);
+            }
+            if values.is_empty() {
+                return Err(format!("line {line}: expected a value"));
+            }
+            if !self.tokens.get(self.at).is_some_and(|t| t.line == line && t.text == ";") {
+                return Err(format!("line {line}: expected ; at the end of the line"));
+            }
+            self.at += 1;
+
+            match range {
+                Some((start, end)) => {
+                    for (i, addr) in (start..=end).enumerate() {
+                        words.push((addr, values[i % values.len()]));
+                    }
+                }
+                None => {
+                    if start + values.len() as u32 > self.depth {
+                        return Err(format!("line {line}: values run past the depth of {}", self.depth));
+                    }
+                    for (i, val) in values.into_iter().enumerate() {
+                        words.push((start + i as u32, val));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parse_mif(text: &str) -> Result<Words, String> {
+    let mut parser = MifParser {
+        tokens: tokenize(text)?,
+        at: 0,
+        depth: 0x10000,
+        width: 16,
+        address_radix: Radix::Hex,
+        data_radix: Radix::Hex,
+    };
+    parser.header()?;
+    parser.content()
+}
+
+/// The initial contents of rom and ram, built up from one or more images.
+pub struct Program {
+    pub rom: Vec<u16>,
+    pub ram: Vec<u16>,
+    /// One past the highest rom address any image set.
+    pub end: usize,
+    /// The ram words any image set.
+    pub loaded: Vec<bool>,
+    /// The rom words any image set.
+    pub rom_loaded: Vec<bool>,
+}
+
+impl Default for Program {
+    /// Rom filled with halt instructions and cleared ram.
+    fn default() -> Program {
+        Program {
+            rom: vec![0x7000; ROM_SIZE],
+            ram: vec![0; RAM_SIZE],
+            end: 0,
+            loaded: vec![false; RAM_SIZE],
+            rom_loaded: vec![false; ROM_SIZE],
+        }
+    }
+}
+
+impl Program {
+    /// Places `words` at `base`. Images can go into rom and ram, not the io window.
+    pub fn place(&mut self, base: u16, words: &[(u32, u16)]) -> Result<(), String> {
+        for &(offset, val) in words {
+            let addr = base as u32 + offset;
+            match addr {
+                0..=0x7FFF => {
+                    self.rom[addr as usize] = val;
+                    self.rom_loaded[addr as usize] = true;
+                    self.end = self.end.max(addr as usize + 1);
+                }
+                0x8000..=0xBFFF => {
+                    self.ram[addr as usize - 0x8000] = val;
+                    self.loaded[addr as usize - 0x8000] = true;
+                }
+                _ => return Err(format!("address {addr:#06x} is outside of rom and ram")),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(Format::from_path("a/prog.MIF"), Ok(Format::Mif));
+        assert_eq!(Format::from_path("prog.ihex"), Ok(Format::IntelHex));
+        assert_eq!(Format::from_path("prog.bin"), Ok(Format::BinLe));
+        assert!(Format::from_path("prog").is_err());
+        assert_eq!("bin-be".parse(), Ok(Format::BinBe));
+    }
+
+    #[test]
+    fn bin() {
+        let data = [0x08, 0x2C, 0x00, 0x70];
+        assert_eq!(parse(&data, Format::BinLe), Ok(vec![(0, 0x2C08), (1, 0x7000)]));
+        assert_eq!(parse(&data, Format::BinBe), Ok(vec![(0, 0x082C), (1, 0x0070)]));
+        assert!(parse(&data[..3], Format::BinLe).is_err());
+    }
+
+    #[test]
+    fn mif() {
+        let text = "\
+-- a comment
+DEPTH = 16; WIDTH = 16;
+ADDRESS_RADIX = HEX; DATA_RADIX = DEC;
+CONTENT
+BEGIN
+0 : 1 2 -1; % a block
+comment %
+[4..6] : 7 8;
+END;
+";
+        assert_eq!(
+            parse(text.as_bytes(), Format::Mif),
+            Ok(vec![(0, 1), (1, 2), (2, 0xFFFF), (4, 7), (5, 8), (6, 7)])
+        );
+    }
+
+    #[test]
+    fn mif_errors() {
+        let mif = |content: &str| parse_mif(&format!("DEPTH = 4;\nCONTENT BEGIN\n{content}\nEND;"));
+        assert_eq!(mif("4 : 0;"), Err("line 3: address 4 is past the depth of 4".to_string()));
+        assert_eq!(mif("3 : 0 0;"), Err("line 3: values run past the depth of 4".to_string()));
+        assert_eq!(mif("[2..1] : 0;"), Err("line 3: the range ends before it starts".to_string()));
+        assert_eq!(mif("0 : 10000;"), Err("line 3: 10000 does not fit in 16 bits".to_string()));
+        assert_eq!(mif("0 : 1\n;"), Err("line 3: expected ; at the end of the line".to_string()));
+        assert!(parse_mif("CONTENT BEGIN 0 : 0;").is_err());
+    }
+
+    #[test]
+    fn hex() {
+        let text = "\
+:040000001234007046
+:020000040001F9
+:02000200ABCD84
+:00000001FF
+";
+        assert_eq!(
+            parse(text.as_bytes(), Format::IntelHex),
+            Ok(vec![(0, 0x1234), (1, 0x0070), (0x10002, 0xABCD)])
+        );
+    }
+
+    #[test]
+    fn hex_errors() {
+        assert_eq!(parse_hex(":040000001234007047\n"), Err("line 1: bad checksum".to_string()));
+        assert_eq!(
+            parse_hex(":040000001234007046\n"),
+            Err("missing the end of file record".to_string())
+        );
+        assert!(parse_hex("0400000012340070E6\n").is_err());
+        assert!(parse_hex(":03000000123400B7\n:00000001FF\n").is_err());
+    }
+
+    #[test]
+    fn place() {
+        let mut program = Program::default();
+        program.place(0x7FFF, &[(0, 0x1111), (1, 0x2222)]).unwrap();
+        assert_eq!(program.rom[0x7FFF], 0x1111);
+        assert_eq!(program.ram[0], 0x2222);
+        assert_eq!(program.end, 0x8000);
+        assert!(program.loaded[0] && !program.loaded[1]);
+        assert!(program.rom_loaded[0x7FFF] && !program.rom_loaded[0x7FFE]);
+        assert!(program.place(0xBFFF, &[(1, 0)]).is_err());
+    }
+}
@@ -1,76 +1,185 @@
 mod args;
-mod devices;
-mod hardware;
+mod debugger;
 mod emulator;
+mod expect;
+mod frames;
+mod gdb;
+mod input;
 mod jit;
+mod profile;
+mod serial;
+mod socket;
+mod trace;
 
 use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-use args::get_args;
-use hardware::def::ROM_SIZE;
-use regex::Regex;
+use args::{get_args, ArgParseError, Options};
+use emu::disasm::disassemble;
+use emu::loader::{parse, Format, Program};
+use emu::symbols::Symbols;
+use emu::Error;
 
+use crate::debugger::debug;
 use crate::emulator::emulate;
+use crate::gdb::gdb;
 use crate::jit::jit;
 
-fn parse_program(prog: &str) -> Vec<u16> {
-    let mut program: Vec<u16> = vec![0x7000; ROM_SIZE];
-
-    /*
-     *  DEPTH = 32768;                -- The size of memory in words
-     *  WIDTH = 16;                   -- The size of data in bits
-     *  ADDRESS_RADIX = HEX;          -- The radix for address values
-     *  DATA_RADIX = HEX;             -- The radix for data values
-     *  CONTENT                       -- start of (address : data pairs)
-     *  BEGIN
-     *  0000 : 2C08; -- imov r12 .isr
-     */
-
-    // assume we are using HEX with depth of 32k and width of 16
-
-    let line_matcher = Regex::new(r"^([0-9a-fA-F]{4}) : ([0-9a-fA-F]{4});.*$").unwrap();
-
-    for line in prog.lines() {
-        let cap_opt = line_matcher.captures(line);
-        if cap_opt.is_none() {
-            continue;
-        }
+fn read_image(path: &str, format: Format) -> Result<Vec<(u32, u16)>, Error> {
+    let data = fs::read(path).map_err(|e| Error::Io(format!("Could not read {path}: {e}")))?;
+    parse(&data, format).map_err(|e| Error::Usage(format!("Could not load {path}: {e}")))
+}
 
-        let caps = cap_opt.unwrap();
-        let addr = caps
-            .get(1)
-            .map(|m| u16::from_str_radix(m.as_str(), 16).unwrap())
-            .unwrap();
-        let val = caps
-            .get(2)
-            .map(|m| u16::from_str_radix(m.as_str(), 16).unwrap())
-            .unwrap();
+/// Splits a `--load` argument, FILE@ADDR with an optional :FORMAT, into its parts.
+fn parse_load(spec: &str) -> Result<(&str, u16, Format), Error> {
+    let bad = || Error::Usage(format!("Bad --load {spec}, expected FILE@ADDR or FILE@ADDR:FORMAT"));
+    let (path, rest) = spec.rsplit_once('@').ok_or_else(bad)?;
+    let (addr, format) = match rest.split_once(':') {
+        Some((addr, format)) => (addr, format.parse::<Format>().map_err(Error::Usage)?),
+        None => (rest, Format::from_path(path).map_err(Error::Usage)?),
+    };
+    let addr = match addr.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => addr.parse::<u16>(),
+    }
+    .map_err(|_| bad())?;
+    Ok((path, addr, format))
+}
 
-        program[usize::from(addr)] = val;
+/// Builds rom and ram from the rom file and every `--load` image, later images win.
+fn load_program(opts: &Options) -> Result<Program, Error> {
+    let format = if opts.format.is_empty() {
+        Format::from_path(&opts.mif_file)
+    } else {
+        opts.format.parse::<Format>()
     }
+    .map_err(Error::Usage)?;
 
+    let mut program = Program::default();
+    let words = read_image(&opts.mif_file, format)?;
     program
-}
+        .place(0, &words)
+        .map_err(|e| Error::Usage(format!("Could not load {}: {e}", opts.mif_file)))?;
+
+    for spec in &opts.load {
+        let (path, addr, format) = parse_load(spec)?;
+        let words = read_image(path, format)?;
+        program
+            .place(addr, &words)
+            .map_err(|e| Error::Usage(format!("Could not load {path} at {addr:#06x}: {e}")))?;
+    }
 
-fn main() -> Result<(), String> {
-    let opts = get_args().map_err(|e| format!("{e}"))?;
+    Ok(program)
+}
 
-    let prog_string =
-        fs::read_to_string(opts.mif_file).expect("Should have been able to read the file");
+/// Reads `--symbols`, or the .sym file next to the rom if there is one.
+fn load_symbols(opts: &Options) -> Result<Symbols, Error> {
+    let path = if opts.symbols.is_empty() {
+        let sibling = Path::new(&opts.mif_file).with_extension("sym");
+        if !sibling.exists() {
+            return Ok(Symbols::default());
+        }
+        sibling.to_string_lossy().into_owned()
+    } else {
+        opts.symbols.clone()
+    };
+    Symbols::load(&path)
+}
 
-    let bytes = prog_string.len();
-    println!("read {bytes} bytes from file");
+/// Loads the program and runs it in the mode `opts` asks for.
+fn run(opts: Options, symbols: &Arc<Symbols>) -> Result<(), Error> {
+    // keep stdout clean for the output of headless, disassembly and expectation modes
+    let quiet = opts.headless || opts.disasm || !opts.expect.is_empty();
 
     // INIT ROM
-    print!("Parsing {bytes} bytes to instructions ... ");
-    let rom: Vec<u16> = parse_program(&prog_string);
-    println!("rom is sized {}", rom.len());
+    let program = load_program(&opts)?;
+
+    if !quiet {
+        println!("rom is sized {}", program.rom.len());
+    }
+
+    if opts.disasm {
+        for (addr, inst) in program.rom.iter().enumerate().take(program.end) {
+            for label in symbols.labels_at(addr as u16) {
+                println!("{label}:");
+            }
+            println!("{addr:04x}: {inst:04x}  {}", disassemble(*inst));
+        }
+        return Ok(());
+    }
 
-    if opts.jit_mode {
-        jit(rom)?;
+    if opts.jit_mode && !opts.trace_file.is_empty() {
+        return Err(Error::Usage("Tracing is only supported by the interpreter, drop --jit".to_string()));
+    }
+    if opts.jit_mode && opts.stop_after > 0 {
+        return Err(Error::Usage("--stop-after is only supported by the interpreter, drop --jit".to_string()));
+    }
+    if (opts.jit_mode || !opts.gdb.is_empty()) && !opts.frames.is_empty() {
+        return Err(Error::Usage("--frames is only supported by the interpreter and debugger".to_string()));
+    }
+    if (opts.jit_mode || !opts.gdb.is_empty() || opts.debug)
+        && !(opts.profile.is_empty() && opts.profile_folded.is_empty())
+    {
+        return Err(Error::Usage("Profiling is only supported by the interpreter".to_string()));
+    }
+    if opts.jit_mode && opts.check {
+        return Err(Error::Usage("--check is only supported by the interpreter, drop --jit".to_string()));
+    }
+    if (opts.jit_mode || !opts.gdb.is_empty()) && !(opts.record.is_empty() && opts.replay.is_empty()) {
+        return Err(Error::Usage("Input recording and replay are not supported with --jit or --gdb".to_string()));
+    }
+    if (opts.jit_mode || !opts.gdb.is_empty() || opts.debug) && !opts.expect.is_empty() {
+        return Err(Error::Usage("--expect is only supported by the interpreter".to_string()));
+    }
+    if opts.jit_mode && (opts.debug || !opts.gdb.is_empty()) {
+        return Err(Error::Usage("--jit can not be used with --debug or --gdb".to_string()));
+    }
+    if !opts.stack_limit.is_empty() && !opts.check {
+        return Err(Error::Usage("--stack-limit only applies with --check".to_string()));
+    }
+    if !opts.trace_range.is_empty() && opts.trace_file.is_empty() {
+        return Err(Error::Usage("--trace-range only applies with --trace".to_string()));
+    }
+    if !opts.record.is_empty() && !opts.replay.is_empty() {
+        return Err(Error::Usage("--record and --replay can not be used together".to_string()));
+    }
+
+    if opts.debug {
+        debug(program, &opts, symbols)?;
+    } else if !opts.gdb.is_empty() {
+        gdb(program, &opts, symbols)?;
+    } else if opts.jit_mode {
+        jit(program, &opts, symbols)?;
     } else {
-        emulate(rom)?;
+        emulate(program, &opts, symbols)?;
     }
 
     Ok(())
 }
+
+fn main() -> ExitCode {
+    let opts = match get_args() {
+        Ok(opts) => opts,
+        // argparse already printed the usage, or what was wrong with the arguments
+        Err(ArgParseError(code)) => return ExitCode::from(code as u8),
+    };
+
+    let symbols = match load_symbols(&opts) {
+        Ok(symbols) => Arc::new(symbols),
+        Err(e) => return fail(&e, &Symbols::EMPTY),
+    };
+    match run(opts, &symbols) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => fail(&e, &symbols),
+    }
+}
+
+fn fail(e: &Error, symbols: &Symbols) -> ExitCode {
+    eprintln!("Error: {}", e.display(symbols));
+    if let Some(fault) = e.fault() {
+        eprintln!("{}", fault.registers());
+    }
+    ExitCode::from(e.exit_code())
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use emu::disasm::disassemble;
+use emu::hardware::def::{JMP, PUSH, ROM_SIZE, RTI};
+use emu::symbols::{Addr, Symbols};
+use emu::{Cpu, Error, Step};
+
+use crate::args::Options;
+
+const SP_INDEX: u16 = 13;
+/// Frames beyond this depth drop the oldest one, so missed returns can not grow the stack forever.
+const MAX_DEPTH: usize = 512;
+/// Single instructions listed at the end of the report.
+const HOT_SPOTS: usize = 20;
+
+/*
+ *  There is no frame pointer to walk, the call stack is rebuilt from the instructions as they
+ *  execute. A frame starts on
+ *
+ *  jmpl r1                   a hardware call, returned from with jmpr
+ *  push t5 t0; jmp r0        a tl call: the return address goes on the return stack in t5
+ *                            and the jump lands on a fn label. tl returns with pop t0 t5; jmp t0
+ *  an interrupt or a trap    returned from with rti
+ *
+ *  Every frame remembers its return address, a jump there drops the frame and all above it.
+ *
+ *  The folded stacks are one line per stack, root first, with the instructions executed in it:
+ *
+ *  reset;fn_main;fn_life::step;fn_life::num_neighbours 51234
+ */
+
+struct Frame {
+    entry: u16,
+    ret: u16,
+}
+
+/// Counts executed instructions per pc and per call stack, for `--profile` and
+/// `--profile-folded`.
+pub struct Profiler {
+    report: Option<(String, BufWriter<File>)>,
+    folded: Option<(String, BufWriter<File>)>,
+    counts: Vec<u64>,
+    insts: Vec<u16>,
+    /// Entry of the function every rom address belongs to, if a symbol covers it.
+    functions: Vec<Option<u16>>,
+    stack: Vec<Frame>,
+    /// The last word pushed through a register other than sp, the return address of tl calls.
+    pushed: u16,
+    // the cpu before the step
+    pc: u16,
+    inst: u16,
+    sp: u16,
+    push_value: u16,
+    /// Instructions executed per stack of function entries, root first.
+    stacks: HashMap<Vec<u16>, u64>,
+    key: Vec<u16>,
+    leaf: Option<u16>,
+    run: u64,
+    stack_changed: bool,
+    symbols: Arc<Symbols>,
+}
+
+fn create(path: &str) -> Result<Option<(String, BufWriter<File>)>, Error> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    let file = File::create(path)
+        .map_err(|e| Error::Io(format!("Could not create profile {path}: {e}")))?;
+    Ok(Some((path.to_string(), BufWriter::new(file))))
+}
+
+/// The label of a function entry, or its address if it has none.
+fn name(entry: u16, symbols: &Symbols) -> String {
+    match symbols.function(entry) {
+        Some((addr, name)) if addr == entry => name.to_string(),
+        _ => symbols
+            .labels_at(entry)
+            .next()
+            .map_or_else(|| format!("{entry:#06x}"), str::to_string),
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    100.0 * count as f64 / total.max(1) as f64
+}
+
+impl Profiler {
+    /// Returns a profiler if `--profile` or `--profile-folded` was given.
+    pub fn from_options(opts: &Options, symbols: &Arc<Symbols>) -> Result<Option<Profiler>, Error> {
+        if opts.profile.is_empty() && opts.profile_folded.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Profiler {
+            report: create(&opts.profile)?,
+            folded: create(&opts.profile_folded)?,
+            counts: vec![0; 0x10000],
+            insts: vec![0; 0x10000],
+            functions: (0..ROM_SIZE as u16)
+                .map(|pc| symbols.function(pc).map(|(entry, _)| entry))
+                .collect(),
+            stack: Vec::new(),
+            pushed: 0,
+            pc: 0,
+            inst: 0,
+            sp: 0,
+            push_value: 0,
+            stacks: HashMap::new(),
+            key: Vec::new(),
+            leaf: None,
+            run: 0,
+            stack_changed: false,
+            symbols: Arc::clone(symbols),
+        }))
+    }
+
+    pub fn before_step(&mut self, cpu: &Cpu) {
+        self.pc = cpu.registers.pc;
+        self.inst = cpu.peek(self.pc).unwrap_or(0);
+        self.sp = cpu.registers.sp;
+        self.push_value = cpu.registers[(self.inst >> 4) & 0xF];
+    }
+
+    /// Counts the instruction and follows calls and returns. Call after every step.
+    pub fn after_step(&mut self, cpu: &Cpu, step: Step) {
+        let pc = cpu.registers.pc;
+        if step == Step::Interrupt || step == Step::Trap {
+            self.call(pc, self.pc);
+            return;
+        }
+
+        self.count();
+
+        let opcode = self.inst >> 12;
+        let r1 = (self.inst >> 8) & 0xF;
+        let r2 = (self.inst >> 4) & 0xF;
+        let sp = cpu.registers.sp;
+        match opcode {
+            PUSH if r1 != SP_INDEX => self.pushed = self.push_value,
+            JMP if r2 & 2 != 0 && sp == self.sp.wrapping_add(1) => self.ret(pc, true),
+            JMP if r2 & 1 != 0 && sp == self.sp.wrapping_sub(1) => {
+                self.call(pc, self.pc.wrapping_add(1))
+            }
+            JMP if r2 & 3 == 0 && pc != self.pc.wrapping_add(1) => {
+                if self.stack.iter().any(|frame| frame.ret == pc) {
+                    self.ret(pc, false);
+                } else if self.is_entry(pc) {
+                    self.call(pc, self.pushed);
+                }
+            }
+            RTI => self.ret(pc, true),
+            _ => (),
+        }
+    }
+
+    fn function(&self, pc: u16) -> Option<u16> {
+        self.functions.get(pc as usize).copied().flatten()
+    }
+
+    fn is_entry(&self, pc: u16) -> bool {
+        self.function(pc) == Some(pc)
+    }
+
+    fn call(&mut self, entry: u16, ret: u16) {
+        if self.stack.len() == MAX_DEPTH {
+            self.stack.remove(0);
+        }
+        self.stack.push(Frame { entry, ret });
+        self.stack_changed = true;
+    }
+
+    /// Drops the frame returning to `pc` and the ones above it. Hardware returns drop the top
+    /// frame even if it was entered some other way.
+    fn ret(&mut self, pc: u16, hardware: bool) {
+        match self.stack.iter().rposition(|frame| frame.ret == pc) {
+            Some(at) => self.stack.truncate(at),
+            None if hardware => {
+                self.stack.pop();
+            }
+            None => return,
+        }
+        self.stack_changed = true;
+    }
+
+    /// Counts the instruction at the pc before the step against the current stack.
+    fn count(&mut self) {
+        self.counts[self.pc as usize] += 1;
+        self.insts[self.pc as usize] = self.inst;
+
+        let leaf = self.function(self.pc);
+        if self.stack_changed || leaf != self.leaf {
+            let mut key: Vec<u16> = self.stack.iter().map(|frame| frame.entry).collect();
+            if let Some(leaf) = leaf {
+                if key.last() != Some(&leaf) {
+                    key.push(leaf);
+                }
+            }
+            if key != self.key {
+                self.flush();
+                self.key = key;
+            }
+            self.leaf = leaf;
+            self.stack_changed = false;
+        }
+        self.run += 1;
+    }
+
+    fn flush(&mut self) {
+        if self.run > 0 {
+            *self.stacks.entry(self.key.clone()).or_default() += self.run;
+            self.run = 0;
+        }
+    }
+
+    /// Writes the report and folded stacks.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.flush();
+        let mut stacks: HashMap<String, u64> = HashMap::new();
+        for (key, count) in &self.stacks {
+            let names: Vec<String> = key
+                .iter()
+                .map(|entry| name(*entry, &self.symbols))
+                .collect();
+            *stacks.entry(names.join(";")).or_default() += count;
+        }
+
+        if let Some((path, out)) = &mut self.folded {
+            let mut lines: Vec<String> = stacks
+                .iter()
+                .map(|(stack, count)| {
+                    let stack = if stack.is_empty() { "[unknown]" } else { stack };
+                    format!("{stack} {count}")
+                })
+                .collect();
+            lines.sort();
+            lines
+                .iter()
+                .try_for_each(|line| writeln!(out, "{line}"))
+                .and_then(|_| out.flush())
+                .map_err(|e| Error::Io(format!("Could not write profile {path}: {e}")))?;
+        }
+
+        if let Some((path, mut out)) = self.report.take() {
+            self.write_report(&mut out, &stacks)
+                .and_then(|_| out.flush())
+                .map_err(|e| Error::Io(format!("Could not write profile {path}: {e}")))?;
+        }
+        Ok(())
+    }
+
+    fn write_report(
+        &self,
+        out: &mut impl Write,
+        stacks: &HashMap<String, u64>,
+    ) -> std::io::Result<()> {
+        let total: u64 = self.counts.iter().sum();
+        // self counts go to the innermost function, total counts to every function on the stack
+        let mut functions: HashMap<&str, (u64, u64)> = HashMap::new();
+        for (stack, count) in stacks {
+            let names: Vec<&str> = stack.split(';').collect();
+            let leaf = names.last().copied().filter(|leaf| !leaf.is_empty());
+            functions.entry(leaf.unwrap_or("[unknown]")).or_default().0 += count;
+            let mut seen: Vec<&str> = Vec::new();
+            for name in names.into_iter().filter(|name| !name.is_empty()) {
+                if !seen.contains(&name) {
+                    functions.entry(name).or_default().1 += count;
+                    seen.push(name);
+                }
+            }
+        }
+        let mut functions: Vec<(&str, (u64, u64))> = functions.into_iter().collect();
+        functions.sort_by(|a, b| {
+            b.1 .0
+                .cmp(&a.1 .0)
+                .then(b.1 .1.cmp(&a.1 .1))
+                .then(a.0.cmp(b.0))
+        });
+
+        writeln!(out, "# toast profile, {total} instructions")?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:>12} {:>7} {:>12} {:>7}  function",
+            "self", "self%", "total", "total%"
+        )?;
+        for (name, (own, inclusive)) in &functions {
+            writeln!(
+                out,
+                "{own:>12} {:>6.2}% {inclusive:>12} {:>6.2}%  {name}",
+                percent(*own, total),
+                percent(*inclusive, total),
+            )?;
+        }
+
+        let mut hot: Vec<usize> = (0..self.counts.len())
+            .filter(|pc| self.counts[*pc] > 0)
+            .collect();
+        hot.sort_by(|a, b| self.counts[*b].cmp(&self.counts[*a]).then(a.cmp(b)));
+        writeln!(out)?;
+        writeln!(out, "{:>12} {:>7}  address", "count", "%")?;
+        for pc in hot.into_iter().take(HOT_SPOTS) {
+            writeln!(
+                out,
+                "{:>12} {:>6.2}%  {}  {}",
+                self.counts[pc],
+                percent(self.counts[pc], total),
+                Addr(pc as u16, &self.symbols),
+                disassemble(self.insts[pc]),
+            )?;
+        }
+        Ok(())
+    }
+}
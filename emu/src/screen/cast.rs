@@ -0,0 +1,113 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::AtomicU16;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::hardware::def::{VGA_HEIGHT, VGA_WIDTH};
+
+use super::{rows, Cell, PALETTE};
+
+/*
+ *  Asciicast v2 files are a json header line followed by one json array per output event:
+ *
+ *  {"version": 2, "width": 100, "height": 60, "timestamp": 1700000000}
+ *  [0.016051, "o", "\u001b[1;1H\u001b[38;2;255;255;255;48;2;0;0;0mHello"]
+ *
+ *  Every frame only moves the cursor to and redraws the cells that changed since the last one,
+ *  frames without changes are left out.
+ */
+
+/// Records frames of the vga screen as an asciinema v2 cast.
+pub struct Cast {
+    out: Box<dyn Write + Send>,
+    prev: Option<Vec<Vec<Cell>>>,
+    start: Instant,
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn set_colors(out: &mut String, cell: &Cell) {
+    let [fr, fg, fb] = PALETTE[cell.fg as usize];
+    let [br, bg, bb] = PALETTE[cell.bg as usize];
+    out.push_str(&format!("\x1b[38;2;{fr};{fg};{fb};48;2;{br};{bg};{bb}m"));
+}
+
+impl Cast {
+    /// Writes the header to `out`, timestamps count from now.
+    pub fn new(mut out: Box<dyn Write + Send>) -> io::Result<Cast> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        writeln!(
+            out,
+            "{{\"version\": 2, \"width\": {VGA_WIDTH}, \"height\": {VGA_HEIGHT}, \"timestamp\": {timestamp}}}"
+        )?;
+        Ok(Cast {
+            out,
+            prev: None,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn create(path: &str) -> Result<Cast, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Could not create cast file {path}: {e}"))?;
+        Cast::new(Box::new(BufWriter::new(file)))
+            .map_err(|e| format!("Could not write cast file {path}: {e}"))
+    }
+
+    /// Records the cells of vram that changed since the last frame.
+    pub fn frame(&mut self, vram: &[AtomicU16]) -> io::Result<()> {
+        let rows = rows(vram);
+        let mut data = String::new();
+        // the terminal cursor and colours after the last cell written
+        let mut cursor: Option<(usize, usize)> = None;
+        let mut colors: Option<(u8, u8)> = None;
+
+        if self.prev.is_none() {
+            data.push_str("\x1b[?25l\x1b[2J");
+        }
+
+        for (line, row) in rows.iter().enumerate() {
+            for (col, cell) in row.iter().enumerate() {
+                if self.prev.as_ref().is_some_and(|prev| prev[line][col] == *cell) {
+                    continue;
+                }
+                if cursor != Some((line, col)) {
+                    data.push_str(&format!("\x1b[{};{}H", line + 1, col + 1));
+                }
+                if colors != Some((cell.fg, cell.bg)) {
+                    set_colors(&mut data, cell);
+                    colors = Some((cell.fg, cell.bg));
+                }
+                data.push(cell.printable());
+                // the cursor stays on the last column instead of wrapping
+                cursor = (col + 1 < VGA_WIDTH).then_some((line, col + 1));
+            }
+        }
+        self.prev = Some(rows);
+
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        writeln!(self.out, "[{time:.6}, \"o\", {}]", json_string(&data))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
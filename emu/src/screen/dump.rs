@@ -0,0 +1,95 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::AtomicU16;
+
+use crate::hardware::def::{VGA_HEIGHT, VGA_WIDTH};
+
+use super::rows;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(DumpFormat::Text),
+            "json" => Ok(DumpFormat::Json),
+            _ => Err(format!("Unknown dump format {s}, expected text or json")),
+        }
+    }
+}
+
+/// Writes the screen as text. The first 60 lines are the characters, then after an empty line,
+/// 60 lines of attributes with two octal digits (fg then bg) per cell.
+fn dump_text(vram: &[AtomicU16], out: &mut dyn Write) -> io::Result<()> {
+    let rows = rows(vram);
+
+    for row in &rows {
+        let line: String = row.iter().map(|c| c.printable()).collect();
+        writeln!(out, "{line}")?;
+    }
+
+    writeln!(out)?;
+
+    for row in &rows {
+        let line: String = row.iter().map(|c| format!("{}{}", c.fg, c.bg)).collect();
+        writeln!(out, "{line}")?;
+    }
+
+    Ok(())
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_list(vals: impl Iterator<Item = u8>) -> String {
+    let vals: Vec<String> = vals.map(|v| v.to_string()).collect();
+    format!("[{}]", vals.join(","))
+}
+
+/// Writes the screen as a json object with one entry per row holding the text and the
+/// foreground and background colour of every cell.
+fn dump_json(vram: &[AtomicU16], out: &mut dyn Write) -> io::Result<()> {
+    let rows = rows(vram);
+
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"width\": {VGA_WIDTH},")?;
+    writeln!(out, "  \"height\": {VGA_HEIGHT},")?;
+    writeln!(out, "  \"rows\": [")?;
+    for (i, row) in rows.iter().enumerate() {
+        let text: String = row.iter().map(|c| c.printable()).collect();
+        let sep = if i + 1 == rows.len() { "" } else { "," };
+        writeln!(
+            out,
+            "    {{\"text\": {}, \"fg\": {}, \"bg\": {}}}{sep}",
+            json_string(&text),
+            json_list(row.iter().map(|c| c.fg)),
+            json_list(row.iter().map(|c| c.bg)),
+        )?;
+    }
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")
+}
+
+pub fn dump(vram: &[AtomicU16], format: DumpFormat, out: &mut dyn Write) -> io::Result<()> {
+    match format {
+        DumpFormat::Text => dump_text(vram, out),
+        DumpFormat::Json => dump_json(vram, out),
+    }
+}
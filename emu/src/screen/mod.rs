@@ -0,0 +1,56 @@
+pub mod cast;
+pub mod dump;
+pub mod raster;
+
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use crate::hardware::def::{VGA_HEIGHT, VGA_WIDTH};
+
+/// Rgb values of the 8 colours a cell can use, indexed by the 3 bit fg and bg fields.
+pub const PALETTE: [[u8; 3]; 8] = [
+    [0, 0, 0],
+    [0, 0, 255],
+    [0, 255, 0],
+    [0, 255, 255],
+    [255, 0, 0],
+    [255, 0, 255],
+    [255, 255, 0],
+    [255, 255, 255],
+];
+
+/// One decoded character cell of the VGA text buffer.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: u8,
+    pub fg: u8,
+    pub bg: u8,
+}
+
+impl Cell {
+    pub fn from_word(val: u16) -> Cell {
+        Cell {
+            ch: (val & 0x00FF) as u8,
+            bg: ((val & 0b0000011100000000) >> 8) as u8,
+            fg: ((val & 0b0011100000000000) >> 11) as u8,
+        }
+    }
+
+    /// The character as shown on the terminal, non printable characters are blanked.
+    pub fn printable(&self) -> char {
+        match self.ch {
+            0x20..=0x7E => self.ch as char,
+            _ => ' ',
+        }
+    }
+}
+
+/// Decodes the visible part of vram into rows of cells.
+pub fn rows(vram: &[AtomicU16]) -> Vec<Vec<Cell>> {
+    (0..VGA_HEIGHT)
+        .map(|line| {
+            (0..VGA_WIDTH)
+                .map(|col| Cell::from_word(vram[line * VGA_WIDTH + col].load(Ordering::Relaxed)))
+                .collect()
+        })
+        .collect()
+}
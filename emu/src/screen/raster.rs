@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::AtomicU16;
+use std::sync::OnceLock;
+
+use crate::hardware::def::{VGA_HEIGHT, VGA_WIDTH};
+
+use super::{rows, PALETTE};
+
+/*
+ *  font/8x12.png is a 1 bit sheet of 16x16 cells, 8x16 pixels each, holding the glyphs for
+ *  characters 0x00 to 0xFF row by row. Only rows 2 to 13 of every cell are drawn, which keeps
+ *  the glyphs in a 8x12 box.
+ */
+
+const SHEET: &[u8] = include_bytes!("../../../font/8x12.png");
+const SHEET_CELL_HEIGHT: usize = 16;
+const GLYPH_TOP: usize = 2;
+
+pub const GLYPH_WIDTH: usize = 8;
+pub const GLYPH_HEIGHT: usize = 12;
+
+/// Size of the rendered screen in pixels.
+pub const WIDTH: usize = VGA_WIDTH * GLYPH_WIDTH;
+pub const HEIGHT: usize = VGA_HEIGHT * GLYPH_HEIGHT;
+
+/// One bool per pixel of every glyph, set where the foreground colour is drawn.
+struct Font {
+    pixels: Vec<bool>,
+}
+
+impl Font {
+    fn load() -> Font {
+        let mut decoder = png::Decoder::new(SHEET);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().expect("font/8x12.png is a valid png");
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).expect("font/8x12.png is a valid png");
+
+        let channels = info.color_type.samples();
+        let sheet_width = info.width as usize;
+        let mut pixels = vec![false; 256 * GLYPH_WIDTH * GLYPH_HEIGHT];
+        for ch in 0..256 {
+            let cell_x = (ch % 16) * GLYPH_WIDTH;
+            let cell_y = (ch / 16) * SHEET_CELL_HEIGHT + GLYPH_TOP;
+            for y in 0..GLYPH_HEIGHT {
+                for x in 0..GLYPH_WIDTH {
+                    let at = ((cell_y + y) * sheet_width + cell_x + x) * channels;
+                    pixels[(ch * GLYPH_HEIGHT + y) * GLYPH_WIDTH + x] = buf[at] >= 0x80;
+                }
+            }
+        }
+        Font { pixels }
+    }
+
+    fn get() -> &'static Font {
+        static FONT: OnceLock<Font> = OnceLock::new();
+        FONT.get_or_init(Font::load)
+    }
+
+    fn pixel(&self, ch: u8, x: usize, y: usize) -> bool {
+        self.pixels[(ch as usize * GLYPH_HEIGHT + y) * GLYPH_WIDTH + x]
+    }
+}
+
+/// Draws vram the way the vga adapter does, every character including the non printable ones
+/// goes through the font. Returns `WIDTH * HEIGHT` rgb pixels, row by row.
+pub fn rasterize(vram: &[AtomicU16]) -> Vec<u8> {
+    let font = Font::get();
+    let mut image = vec![0; WIDTH * HEIGHT * 3];
+
+    for (line, row) in rows(vram).iter().enumerate() {
+        for (col, cell) in row.iter().enumerate() {
+            for y in 0..GLYPH_HEIGHT {
+                for x in 0..GLYPH_WIDTH {
+                    let color = if font.pixel(cell.ch, x, y) {
+                        PALETTE[cell.fg as usize]
+                    } else {
+                        PALETTE[cell.bg as usize]
+                    };
+                    let at = ((line * GLYPH_HEIGHT + y) * WIDTH + col * GLYPH_WIDTH + x) * 3;
+                    image[at..at + 3].copy_from_slice(&color);
+                }
+            }
+        }
+    }
+
+    image
+}
+
+/// Encodes the rendered screen as a png.
+pub fn write_png(vram: &[AtomicU16], out: &mut dyn Write) -> Result<(), String> {
+    let mut encoder = png::Encoder::new(out, WIDTH as u32, HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&rasterize(vram)))
+        .map_err(|e| format!("Could not encode screenshot: {e}"))
+}
+
+/// Renders the screen to a png file at `path`.
+pub fn save_png(vram: &[AtomicU16], path: &str) -> Result<(), String> {
+    let file = File::create(path)
+        .map_err(|e| format!("Could not create screenshot {path}: {e}"))?;
+    let mut out = BufWriter::new(file);
+    write_png(vram, &mut out)?;
+    out.flush()
+        .map_err(|e| format!("Could not write screenshot {path}: {e}"))
+}
@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::{self, stdin, stdout};
+use std::os::unix::net::UnixListener;
+
+use emu::devices::{uart, Uart};
+use emu::hardware::def::UART_BASE;
+use emu::{Cpu, Error};
+
+use crate::args::Options;
+use crate::socket;
+
+/// Where the other end of the uart is, from `--uart`.
+enum Host {
+    /// Process stdin and stdout, only in headless mode.
+    Stdio,
+    /// Sent bytes are written to a file, nothing is received.
+    File(String),
+    /// The first client to connect to a unix socket. A socket left at the path by an earlier
+    /// run is replaced, other files are not.
+    Unix(String),
+}
+
+impl Host {
+    fn parse(spec: &str) -> Result<Host, String> {
+        if spec == "stdio" {
+            Ok(Host::Stdio)
+        } else if let Some(path) = spec.strip_prefix("file:") {
+            Ok(Host::File(path.to_string()))
+        } else if let Some(path) = spec.strip_prefix("unix:") {
+            Ok(Host::Unix(path.to_string()))
+        } else {
+            Err(format!("Unknown uart host {spec}, expected stdio, file:PATH or unix:PATH"))
+        }
+    }
+}
+
+/// Attaches a uart at `UART_BASE` if `--uart` is given, waiting for a client first when it is
+/// bridged to a socket.
+pub fn attach(cpu: &mut Cpu, opts: &Options) -> Result<(), Error> {
+    if opts.uart.is_empty() {
+        return Ok(());
+    }
+
+    let device = match Host::parse(&opts.uart).map_err(Error::Usage)? {
+        Host::Stdio => {
+            if !opts.headless || opts.debug {
+                return Err(Error::Usage(
+                    "--uart stdio needs --headless, and can not be used with --debug".to_string(),
+                ));
+            }
+            if opts.dump_file == "-" {
+                return Err(Error::Usage(
+                    "--uart stdio needs the screen dump to go to a file, pass --dump".to_string(),
+                ));
+            }
+            Uart::new(stdin(), Box::new(stdout()))
+        }
+        Host::File(path) => {
+            let file = File::create(&path)
+                .map_err(|e| Error::Io(format!("Could not create uart output file {path}: {e}")))?;
+            Uart::new(io::empty(), Box::new(file))
+        }
+        Host::Unix(path) => {
+            socket::remove_stale(&path)?;
+            let listener = UnixListener::bind(&path)
+                .map_err(|e| Error::Io(format!("Could not listen for the uart on {path}: {e}")))?;
+            eprintln!("waiting for a uart connection on {path}");
+            let (stream, _) = listener.accept().map_err(|e| {
+                Error::Io(format!("Could not accept a uart connection on {path}: {e}"))
+            })?;
+            let rx = stream.try_clone().map_err(|e| {
+                Error::Io(format!("Could not set up the uart connection on {path}: {e}"))
+            })?;
+            Uart::new(rx, Box::new(stream))
+        }
+    };
+
+    cpu.mem
+        .attach(UART_BASE, UART_BASE + uart::SIZE - 1, Box::new(device))
+        .map_err(Error::Usage)
+}
@@ -0,0 +1,291 @@
+use std::fs;
+use std::sync::atomic::Ordering;
+
+use crate::cpu::Cpu;
+use crate::devices::exceptions;
+use crate::error::Error;
+use crate::hardware::def::{RAM_SIZE, VGA_HEIGHT, VGA_WIDTH};
+
+/*
+ *  Snapshot file layout, all values little endian:
+ *
+ *  "TOASTSNP"                    -- magic
+ *  u16 version
+ *  u16 * 16                      -- registers, in encoding order
+ *  u8                            -- pending irq
+ *  u16                           -- last key read from the keyboard
+ *  u16                           -- modifier keys held, see KeyFifo::held
+ *  u16                           -- keys waiting in the keyboard fifo, n
+ *  u16 * n                       -- waiting keys, oldest first
+ *  u16 * 3                       -- interrupt controller pending, mask and source
+ *  u16 * 6                       -- exception unit registers
+ *  u16                           -- devices with state, n
+ *  n * (u16 base, u16 len, u16 * len)
+ *                                -- base address and state of each, see Device::state
+ *  u64                           -- instructions executed so far
+ *  u16 * RAM_SIZE                -- ram
+ *  u16 * VGA_WIDTH * VGA_HEIGHT  -- vram
+ */
+
+const MAGIC: &[u8; 8] = b"TOASTSNP";
+const VERSION: u16 = 1;
+
+const VRAM_SIZE: usize = VGA_WIDTH * VGA_HEIGHT;
+
+/// The complete state of a running machine, apart from the rom.
+pub struct Snapshot {
+    registers: [u16; 16],
+    irq: bool,
+    key: u16,
+    held: u16,
+    keys: Vec<u16>,
+    interrupts: [u16; 3],
+    exceptions: [u16; exceptions::SIZE as usize],
+    devices: Vec<(u16, Vec<u16>)>,
+    count: u64,
+    ram: Vec<u16>,
+    vram: Vec<u16>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < n {
+            return Err("Snapshot file is truncated".to_string());
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn words(&mut self, n: usize) -> Result<Vec<u16>, String> {
+        (0..n).map(|_| self.u16()).collect()
+    }
+}
+
+impl Snapshot {
+    pub fn capture(cpu: &Cpu) -> Snapshot {
+        let fifo = cpu.mem.key_fifo();
+        Snapshot {
+            registers: cpu.registers.values(),
+            irq: cpu.irq_pending(),
+            key: fifo.last(),
+            held: fifo.held(),
+            keys: fifo.keys().collect(),
+            interrupts: cpu.mem.interrupts().state(),
+            exceptions: cpu.mem.exceptions().state(),
+            devices: cpu.mem.device_states(),
+            count: cpu.count(),
+            ram: cpu.mem.ram().to_vec(),
+            vram: cpu
+                .mem
+                .vram()
+                .iter()
+                .map(|w| w.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+
+    /// Puts the machine back into the captured state. The devices the snapshot has state for
+    /// have to be attached at the same addresses.
+    pub fn restore(&self, cpu: &mut Cpu) -> Result<(), Error> {
+        cpu.mem
+            .restore_devices(&self.devices)
+            .map_err(Error::Usage)?;
+        cpu.registers.set_values(&self.registers);
+        cpu.set_irq_pending(self.irq);
+        cpu.set_count(self.count);
+        cpu.mem.key_fifo().restore(self.key, self.held, &self.keys);
+        cpu.mem.interrupts_mut().restore(self.interrupts);
+        cpu.mem.exceptions_mut().restore(self.exceptions);
+        cpu.mem.ram_mut().copy_from_slice(&self.ram);
+        if let Some(checks) = cpu.mem.checks_mut() {
+            checks.initialize_all();
+        }
+        for (word, val) in cpu.mem.vram().iter().zip(&self.vram) {
+            word.store(*val, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 2 * (RAM_SIZE + VRAM_SIZE) + 64);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        for val in self.registers {
+            bytes.extend_from_slice(&val.to_le_bytes());
+        }
+        bytes.push(u8::from(self.irq));
+        bytes.extend_from_slice(&self.key.to_le_bytes());
+        bytes.extend_from_slice(&self.held.to_le_bytes());
+        bytes.extend_from_slice(&(self.keys.len() as u16).to_le_bytes());
+        for val in &self.keys {
+            bytes.extend_from_slice(&val.to_le_bytes());
+        }
+        for val in self.interrupts {
+            bytes.extend_from_slice(&val.to_le_bytes());
+        }
+        for val in self.exceptions {
+            bytes.extend_from_slice(&val.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.devices.len() as u16).to_le_bytes());
+        for (start, state) in &self.devices {
+            bytes.extend_from_slice(&start.to_le_bytes());
+            bytes.extend_from_slice(&(state.len() as u16).to_le_bytes());
+            for val in state {
+                bytes.extend_from_slice(&val.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        for val in self.ram.iter().chain(&self.vram) {
+            bytes.extend_from_slice(&val.to_le_bytes());
+        }
+
+        fs::write(path, bytes)
+            .map_err(|e| Error::Io(format!("Could not write snapshot {path}: {e}")))
+    }
+
+    pub fn load(path: &str) -> Result<Snapshot, Error> {
+        let bytes = fs::read(path)
+            .map_err(|e| Error::Io(format!("Could not read snapshot {path}: {e}")))?;
+        Snapshot::parse(path, &bytes).map_err(Error::Usage)
+    }
+
+    fn parse(path: &str, bytes: &[u8]) -> Result<Snapshot, String> {
+        let mut reader = Reader { bytes };
+
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(format!("{path} is not a snapshot file"));
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(format!(
+                "Snapshot {path} has version {version}, only version {VERSION} is supported"
+            ));
+        }
+
+        let registers: [u16; 16] = reader.words(16)?.try_into().unwrap();
+        let irq = reader.take(1)?[0] != 0;
+        let key = reader.u16()?;
+        let held = reader.u16()?;
+        let n = reader.u16()?;
+        let keys = reader.words(n as usize)?;
+        let interrupts = reader.words(3)?.try_into().unwrap();
+        let exceptions = reader.words(exceptions::SIZE as usize)?.try_into().unwrap();
+        let n = reader.u16()?;
+        let devices = (0..n)
+            .map(|_| {
+                let start = reader.u16()?;
+                let len = reader.u16()?;
+                Ok((start, reader.words(len as usize)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let count = reader.u64()?;
+        let ram = reader.words(RAM_SIZE)?;
+        let vram = reader.words(VRAM_SIZE)?;
+
+        Ok(Snapshot {
+            registers,
+            irq,
+            key,
+            held,
+            keys,
+            interrupts,
+            exceptions,
+            devices,
+            count,
+            ram,
+            vram,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::timer;
+    use crate::hardware::def::{ROM_SIZE, TIMER_BASE};
+
+    fn machine() -> Cpu {
+        let mut cpu = Cpu::new(vec![0x7000; ROM_SIZE]);
+        let mut registers = [0; 16];
+        registers
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, r)| *r = i as u16 * 0x0101);
+        cpu.registers.set_values(&registers);
+        cpu.set_count(1234);
+        cpu.mem.ram_mut()[5] = 0xBEEF;
+        cpu.mem.vram()[3].store(0x0041, Ordering::Relaxed);
+        cpu.mem.key_fifo().receive(0x0012);
+        cpu.mem.key_fifo().receive(0x001C);
+        cpu.mem.key_fifo().receive(0xE075);
+        cpu.mem.interrupts_mut().restore([0x0001, 0xFFFE, 2]);
+        cpu.mem.write(TIMER_BASE + timer::RELOAD, 100).unwrap();
+        cpu
+    }
+
+    /// The saved snapshot of `cpu`, going through a file named after the test.
+    fn bytes(cpu: &Cpu, name: &str) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("emu-{}-{name}", std::process::id()));
+        let path = path.to_str().unwrap();
+        Snapshot::capture(cpu).save(path).unwrap();
+        let bytes = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let saved = bytes(&machine(), "round_trip");
+        let mut cpu = Cpu::new(vec![0x7000; ROM_SIZE]);
+        Snapshot::parse("test", &saved)
+            .unwrap()
+            .restore(&mut cpu)
+            .unwrap();
+
+        assert_eq!(cpu.registers.values()[15], 0x0F0F);
+        assert_eq!(cpu.count(), 1234);
+        assert_eq!(cpu.mem.ram()[5], 0xBEEF);
+        assert_eq!(cpu.mem.vram()[3].load(Ordering::Relaxed), 0x0041);
+        assert_eq!(
+            cpu.mem.key_fifo().keys().collect::<Vec<_>>(),
+            [0x0012, 0x001C, 0xE075]
+        );
+        assert_eq!(cpu.mem.key_fifo().held(), 1);
+        assert_eq!(cpu.mem.interrupts().state(), [0x0001, 0xFFFE, 2]);
+        assert_eq!(cpu.mem.peek(TIMER_BASE + timer::RELOAD).unwrap(), 100);
+        assert_eq!(bytes(&cpu, "round_trip"), saved);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut saved = bytes(&machine(), "rejects_other_files");
+        assert_eq!(
+            Snapshot::parse("test", &saved[..saved.len() - 1])
+                .err()
+                .unwrap(),
+            "Snapshot file is truncated"
+        );
+        saved[MAGIC.len()] = 2;
+        assert_eq!(
+            Snapshot::parse("test", &saved).err().unwrap(),
+            "Snapshot test has version 2, only version 1 is supported"
+        );
+        assert_eq!(
+            Snapshot::parse("test", b"not a snapshot").err().unwrap(),
+            "test is not a snapshot file"
+        );
+    }
+}
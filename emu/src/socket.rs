@@ -0,0 +1,46 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+
+use emu::Error;
+
+/// Removes a unix socket an earlier run left at `path`, so it can be bound again. Anything else
+/// there is reported instead of deleted, a mistyped path must not cost the user a file.
+pub fn remove_stale(path: &str) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)
+            .map_err(|e| Error::Io(format!("Could not remove the old socket {path}: {e}"))),
+        Ok(_) => Err(Error::Usage(format!(
+            "{path} already exists and is not a socket, not replacing it"
+        ))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::Io(format!("Could not check {path}: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+
+    fn path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("emu-{}-{name}", std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn replaces_sockets_only() {
+        let file = path("file");
+        fs::write(&file, "keep").unwrap();
+        assert!(matches!(remove_stale(&file), Err(Error::Usage(_))));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+        fs::remove_file(&file).unwrap();
+
+        let sock = path("sock");
+        drop(UnixListener::bind(&sock).unwrap());
+        remove_stale(&sock).unwrap();
+        assert!(fs::symlink_metadata(&sock).is_err());
+        remove_stale(&sock).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+//! Symbol tables, so addresses can be printed as labels. The table of a run is kept on the
+//! memory bus, everything that prints an address goes through `Addr`.
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+
+use crate::error::Error;
+
+/*
+ *  Symbol files are text, one symbol per line, as written by the assembler's --symbol_file:
+ *
+ *  # toast symbols
+ *  0023 fn fn_main               -- address, kind, name. fn labels start a function
+ *  002a code fn_main_body_if_0
+ *  8000 data ret_stack 400       -- data symbols can give their size in words, in hex
+ *  FFFE const KEY_STATUS         -- constants are never used to name addresses
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Function,
+    Code,
+    Data,
+    Const,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub addr: u16,
+    pub kind: Kind,
+    pub name: String,
+    pub size: Option<u16>,
+}
+
+#[derive(Debug, Default)]
+pub struct Symbols {
+    /// Sorted by address.
+    symbols: Vec<Symbol>,
+    /// Whether any symbol has kind fn.
+    functions: bool,
+}
+
+fn parse_line(line: &str) -> Result<Symbol, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if !(3..=4).contains(&words.len()) {
+        return Err("expected ADDR KIND NAME [SIZE]".to_string());
+    }
+    let hex = |s: &str| u16::from_str_radix(s, 16).map_err(|_| format!("{s} is not a hex number"));
+    let kind = match words[1] {
+        "fn" => Kind::Function,
+        "code" => Kind::Code,
+        "data" => Kind::Data,
+        "const" => Kind::Const,
+        k => {
+            return Err(format!(
+                "unknown symbol kind {k}, expected fn, code, data or const"
+            ))
+        }
+    };
+    Ok(Symbol {
+        addr: hex(words[0])?,
+        kind,
+        name: words[2].to_string(),
+        size: words.get(3).map(|s| hex(s)).transpose()?,
+    })
+}
+
+/// Rom and ram are separate regions, a label never covers addresses in the other one.
+fn region(addr: u16) -> u16 {
+    match addr {
+        0..=0x7FFF => 0,
+        0x8000..=0xBFFF => 1,
+        _ => 2,
+    }
+}
+
+impl Symbols {
+    /// A table without symbols, for printing plain addresses.
+    pub const EMPTY: Symbols = Symbols {
+        symbols: Vec::new(),
+        functions: false,
+    };
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            symbols.push(parse_line(line).map_err(|e| format!("line {}: {e}", i + 1))?);
+        }
+        symbols.sort_by_key(|s: &Symbol| s.addr);
+        let functions = symbols.iter().any(|s| s.kind == Kind::Function);
+        Ok(Symbols { symbols, functions })
+    }
+
+    pub fn load(path: &str) -> Result<Symbols, Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::Io(format!("Could not read symbol file {path}: {e}")))?;
+        Symbols::parse(&text)
+            .map_err(|e| Error::Usage(format!("Could not load symbol file {path}: {e}")))
+    }
+
+    /// The closest code or data symbol at or below `addr` in the same region, and the offset
+    /// from it. Data symbols with a size only cover their own words.
+    pub fn lookup(&self, addr: u16) -> Option<(&str, u16)> {
+        let below = self.symbols.partition_point(|s| s.addr <= addr);
+        self.symbols[..below]
+            .iter()
+            .rev()
+            .filter(|s| s.kind != Kind::Const)
+            .take_while(|s| region(s.addr) == region(addr))
+            .find(|s| s.size.is_none_or(|size| addr - s.addr < size))
+            .map(|s| (s.name.as_str(), addr - s.addr))
+    }
+
+    /// One past the highest ram word covered by a data symbol with a size, where the globals of
+    /// a compiled program end.
+    pub fn data_end(&self) -> Option<u16> {
+        self.symbols
+            .iter()
+            .filter(|s| s.kind == Kind::Data && region(s.addr) == 1)
+            .filter_map(|s| s.size.map(|size| s.addr.saturating_add(size)))
+            .max()
+    }
+
+    /// Whether a code or data symbol covers `addr` the way `lookup` finds them, or a constant
+    /// sits at exactly `addr`.
+    pub fn covers(&self, addr: u16) -> bool {
+        self.lookup(addr).is_some()
+            || self
+                .symbols
+                .iter()
+                .any(|s| s.kind == Kind::Const && s.addr == addr)
+    }
+
+    /// The address of the symbol called `name`, constants included.
+    pub fn find(&self, name: &str) -> Option<u16> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    /// Names of the code symbols at exactly `addr`.
+    pub fn labels_at(&self, addr: u16) -> impl Iterator<Item = &str> {
+        let start = self.symbols.partition_point(|s| s.addr < addr);
+        self.symbols[start..]
+            .iter()
+            .take_while(move |s| s.addr == addr)
+            .filter(|s| matches!(s.kind, Kind::Function | Kind::Code))
+            .map(|s| s.name.as_str())
+    }
+
+    /// The function `addr` is part of: the closest fn symbol at or below it in rom, as address
+    /// and name. Tables without fn symbols, from hand written assembly, use every code label.
+    pub fn function(&self, addr: u16) -> Option<(u16, &str)> {
+        let kind = if self.functions {
+            Kind::Function
+        } else {
+            Kind::Code
+        };
+        let below = self.symbols.partition_point(|s| s.addr <= addr);
+        self.symbols[..below]
+            .iter()
+            .rev()
+            .take_while(|s| region(s.addr) == region(addr))
+            .find(|s| s.kind == kind)
+            .map(|s| (s.addr, s.name.as_str()))
+    }
+}
+
+/// Formats an address as `0x03a7 <fn_main+0x4>` when a symbol of the table covers it, or
+/// `0x03a7` otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Addr<'a>(pub u16, pub &'a Symbols);
+
+impl Display for Addr<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#06x}", self.0)?;
+        match self.1.lookup(self.0) {
+            Some((name, 0)) => write!(f, " <{name}>"),
+            Some((name, offset)) => write!(f, " <{name}+{offset:#x}>"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "\
+# toast symbols
+0023 fn fn_main
+002a code fn_main_body_if_0
+0010 fn init_globals   # out of order
+8000 data ret_stack 400
+8400 data counter 1
+FFFE const KEY_STATUS
+";
+
+    #[test]
+    fn parse() {
+        let symbols = Symbols::parse(TABLE).unwrap();
+        assert_eq!(symbols.find("KEY_STATUS"), Some(0xFFFE));
+        assert_eq!(symbols.find("init_globals"), Some(0x0010));
+        assert_eq!(symbols.find("missing"), None);
+        assert_eq!(symbols.data_end(), Some(0x8401));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            Symbols::parse("0023 fn\n").unwrap_err(),
+            "line 1: expected ADDR KIND NAME [SIZE]"
+        );
+        assert_eq!(
+            Symbols::parse("\n0023 label main\n").unwrap_err(),
+            "line 2: unknown symbol kind label, expected fn, code, data or const"
+        );
+        assert_eq!(
+            Symbols::parse("8000 data buf 4g\n").unwrap_err(),
+            "line 1: 4g is not a hex number"
+        );
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = Symbols::parse(TABLE).unwrap();
+        assert_eq!(symbols.lookup(0x0023), Some(("fn_main", 0)));
+        assert_eq!(symbols.lookup(0x0025), Some(("fn_main", 2)));
+        assert_eq!(symbols.lookup(0x7FFF), Some(("fn_main_body_if_0", 0x7FD5)));
+        assert_eq!(symbols.lookup(0x0005), None);
+        // sized data only covers its own words, and labels stay in their region
+        assert_eq!(symbols.lookup(0x83FF), Some(("ret_stack", 0x3FF)));
+        assert_eq!(symbols.lookup(0x8401), None);
+        assert_eq!(symbols.lookup(0xFFFE), None);
+        assert!(symbols.covers(0xFFFE) && symbols.covers(0x0025) && !symbols.covers(0xFFFD));
+    }
+
+    #[test]
+    fn functions() {
+        let symbols = Symbols::parse(TABLE).unwrap();
+        assert_eq!(symbols.function(0x002b), Some((0x0023, "fn_main")));
+        assert_eq!(symbols.function(0x8000), None);
+        assert_eq!(
+            symbols.labels_at(0x002a).collect::<Vec<_>>(),
+            ["fn_main_body_if_0"]
+        );
+
+        // hand written assembly has no fn symbols, every code label starts a function
+        let symbols = Symbols::parse("0000 code start\n0008 code loop\n").unwrap();
+        assert_eq!(symbols.function(0x000a), Some((0x0008, "loop")));
+    }
+
+    #[test]
+    fn addr() {
+        let symbols = Symbols::parse(TABLE).unwrap();
+        assert_eq!(Addr(0x0023, &symbols).to_string(), "0x0023 <fn_main>");
+        assert_eq!(Addr(0x0024, &symbols).to_string(), "0x0024 <fn_main+0x1>");
+        assert_eq!(Addr(0x0024, &Symbols::EMPTY).to_string(), "0x0024");
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use emu::devices::{exceptions, Access};
+use emu::disasm::disassemble;
+use emu::hardware::def::EXC_BASE;
+use emu::hardware::register::REGISTER_NAMES;
+use emu::symbols::{Addr, Symbols};
+use emu::{Cpu, Error, Step};
+
+use crate::args::Options;
+
+const FLAG_NAMES: [(u16, &str); 6] = [
+    (0x20, "I"),
+    (0x10, "V"),
+    (0x08, "C"),
+    (0x04, "N"),
+    (0x02, "Z"),
+    (0x01, "X"),
+];
+
+const SR_INDEX: usize = 14;
+const PC_INDEX: usize = 15;
+
+pub fn parse_addr(s: &str) -> Result<u16, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse::<u16>(),
+    };
+    parsed.map_err(|_| format!("{s} is not an address"))
+}
+
+fn write_error(e: std::io::Error) -> Error {
+    Error::Io(format!("Could not write trace: {e}"))
+}
+
+/// Parses a comma separated list of inclusive `START:END` pc ranges.
+fn parse_ranges(s: &str) -> Result<Vec<(u16, u16)>, String> {
+    s.split(',')
+        .map(|range| {
+            let (start, end) = range
+                .split_once(':')
+                .ok_or_else(|| format!("Trace range {range} should look like START:END"))?;
+            Ok((parse_addr(start)?, parse_addr(end)?))
+        })
+        .collect()
+}
+
+/// Writes one line per executed instruction to the trace file, or keeps the last few lines in
+/// memory and only writes them out when the program fails. Driven by the interpreter loop
+/// through `before_step`, `after_step` and `finish`.
+pub struct Tracer {
+    out: BufWriter<File>,
+    ranges: Vec<(u16, u16)>,
+    ring: Option<VecDeque<String>>,
+    ring_size: usize,
+    /// Instruction count, registers and instruction of the step being traced.
+    count: u64,
+    before: [u16; 16],
+    inst: Option<u16>,
+}
+
+impl Tracer {
+    /// Returns a tracer if `--trace` was given.
+    pub fn from_options(opts: &Options) -> Result<Option<Tracer>, Error> {
+        if opts.trace_file.is_empty() {
+            return Ok(None);
+        }
+
+        let file = File::create(&opts.trace_file).map_err(|e| {
+            Error::Io(format!(
+                "Could not create trace file {}: {e}",
+                opts.trace_file
+            ))
+        })?;
+        let ranges = if opts.trace_range.is_empty() {
+            Vec::new()
+        } else {
+            parse_ranges(&opts.trace_range).map_err(Error::Usage)?
+        };
+        let ring = (opts.trace_ring > 0).then(|| VecDeque::with_capacity(opts.trace_ring));
+
+        Ok(Some(Tracer {
+            out: BufWriter::new(file),
+            ranges,
+            ring,
+            ring_size: opts.trace_ring,
+            count: 0,
+            before: [0; 16],
+            inst: None,
+        }))
+    }
+
+    fn wanted(&self, pc: u16) -> bool {
+        self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&pc))
+    }
+
+    fn format(
+        count: u64,
+        before: &[u16; 16],
+        after: &[u16; 16],
+        inst: Option<u16>,
+        accesses: &[Access],
+        symbols: &Symbols,
+    ) -> String {
+        let pc = before[PC_INDEX];
+        let mut line = match inst {
+            Some(inst) => format!(
+                "{count:>10} {}: {inst:04x}  {:<20}",
+                Addr(pc, symbols),
+                disassemble(inst)
+            ),
+            None => format!(
+                "{count:>10} {}: ----  {:<20}",
+                Addr(pc, symbols),
+                "interrupt"
+            ),
+        };
+
+        let regs: Vec<String> = (0..PC_INDEX)
+            .filter(|r| *r != SR_INDEX && before[*r] != after[*r])
+            .map(|r| format!("{}={:#06x}", REGISTER_NAMES[r], after[r]))
+            .collect();
+        line.push_str(&format!(" | {}", regs.join(" ")));
+
+        let changed = before[SR_INDEX] ^ after[SR_INDEX];
+        let flags: Vec<String> = FLAG_NAMES
+            .iter()
+            .filter(|(mask, _)| changed & mask != 0)
+            .map(|(mask, name)| {
+                format!(
+                    "{name}{}",
+                    if after[SR_INDEX] & mask != 0 {
+                        '+'
+                    } else {
+                        '-'
+                    }
+                )
+            })
+            .collect();
+        line.push_str(&format!(" | {}", flags.join(" ")));
+
+        let mem: Vec<String> = accesses
+            .iter()
+            .map(|access| match access {
+                Access::Read { addr, val } => format!("r[{}]={val:#06x}", Addr(*addr, symbols)),
+                Access::Write { addr, val } => format!("w[{}]={val:#06x}", Addr(*addr, symbols)),
+            })
+            .collect();
+        line.push_str(&format!(" | {}", mem.join(" ")));
+        line
+    }
+
+    fn emit(&mut self, line: String) -> Result<(), Error> {
+        match &mut self.ring {
+            Some(ring) => {
+                if ring.len() == self.ring_size {
+                    ring.pop_front();
+                }
+                ring.push_back(line);
+                Ok(())
+            }
+            None => writeln!(self.out, "{line}").map_err(write_error),
+        }
+    }
+
+    /// Starts logging the memory accesses of `cpu`. Call before the first step.
+    pub fn start(&mut self, cpu: &mut Cpu) {
+        cpu.mem.log_accesses();
+    }
+
+    /// Remembers the state the step starts from. Call before every step.
+    pub fn before_step(&mut self, cpu: &Cpu) {
+        self.count = cpu.count();
+        self.before = cpu.registers.values();
+        self.inst = cpu.peek(cpu.registers.pc).ok();
+    }
+
+    /// Logs the step that gave `result`. Call after every step, before anything else touches
+    /// memory.
+    pub fn after_step(&mut self, cpu: &mut Cpu, result: &Result<Step, Error>) -> Result<(), Error> {
+        let pc = self.before[PC_INDEX];
+        let mut accesses = cpu.mem.take_accesses();
+        // drop the instruction fetch, an interrupt starts with a write instead
+        if let Some(Access::Read { addr, .. }) = accesses.first() {
+            if *addr == pc {
+                accesses.remove(0);
+            }
+        }
+        if !self.wanted(pc) {
+            return Ok(());
+        }
+
+        let inst = if matches!(result, Ok(Step::Interrupt)) {
+            None
+        } else {
+            self.inst
+        };
+        let after = cpu.registers.values();
+        let symbols = cpu.mem.symbols();
+        let mut line = Tracer::format(self.count, &self.before, &after, inst, &accesses, symbols);
+        match result {
+            Ok(Step::Trap) => {
+                let cause = cpu.peek(EXC_BASE + exceptions::CAUSE).unwrap_or(0);
+                line.push_str(&format!(" | trap: {}", exceptions::cause_name(cause)));
+            }
+            Err(e) => line.push_str(&format!(" | error: {}", e.display(symbols))),
+            Ok(_) => (),
+        }
+        self.emit(line)
+    }
+
+    /// Writes out the ring buffer and flushes the trace file. A ring is only kept for post
+    /// mortems, it is dropped unless the run `failed`.
+    pub fn finish(&mut self, failed: bool) -> Result<(), Error> {
+        if let Some(ring) = self.ring.take().filter(|_| failed) {
+            for line in ring {
+                writeln!(self.out, "{line}").map_err(write_error)?;
+            }
+        }
+        self.out.flush().map_err(write_error)
+    }
+}